@@ -0,0 +1,222 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{future::Future, path::PathBuf, sync::Arc};
+
+use tokio::task;
+use uuid::Uuid;
+
+use crate::engine::{
+    chunk::Chunk,
+    error::XEngineError,
+    shared::SharedVolume,
+    volume::Volume,
+    xfile::{XFile, XFileChunks, XFileQuery},
+};
+
+/*
+    Async counterparts of ChunksHandler and XFileHandler.
+
+    Volume I/O is blocking, so every call is moved to the tokio blocking pool and
+    the runtime worker threads only await its completion.
+*/
+pub trait AsyncChunksHandler {
+    fn get_chunk(&self, uuid: String) -> impl Future<Output = Result<Option<Chunk>, XEngineError>> + Send + use<Self>;
+    fn add_chunk(&self, chunk: Chunk) -> impl Future<Output = Result<Option<String>, XEngineError>> + Send + use<Self>;
+    fn add_chunks(&self, chunks: Vec<Chunk>) -> impl Future<Output = Result<(), XEngineError>> + Send + use<Self>;
+    fn remove_chunk(&self, uuid: String) -> impl Future<Output = Result<Option<String>, XEngineError>> + Send + use<Self>;
+}
+
+pub trait AsyncXFileHandler {
+    fn find_file_chunks(&self, query: XFileQuery) -> impl Future<Output = Result<Option<XFileChunks>, XEngineError>> + Send + use<Self>;
+    fn import_file(&self, user_uid: Uuid, path: PathBuf, vfolder: String) -> impl Future<Output = Result<XFile, XEngineError>> + Send + use<Self>;
+    fn export_file(&self, file: XFile, path: PathBuf) -> impl Future<Output = Result<(), XEngineError>> + Send + use<Self>;
+}
+
+async fn spawn_blocking<T, F>(f: F) -> Result<T, XEngineError>
+where
+    F: FnOnce() -> Result<T, XEngineError> + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => return result,
+        Err(err) => return Err(XEngineError::TaskFailed(err.to_string())),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AsyncVolume {
+    shared: Arc<SharedVolume>,
+}
+
+impl AsyncVolume {
+    pub fn new(shared: SharedVolume) -> Self {
+        return Self {
+            shared: Arc::new(shared),
+        };
+    }
+
+    pub async fn open(mut volume: Volume, write: bool) -> Result<Self, XEngineError> {
+        return spawn_blocking(move || {
+            let file = volume.open(write)?;
+
+            return Ok(Self::new(SharedVolume::new(volume, file)));
+        })
+        .await;
+    }
+
+    pub async fn open_existing(path: String, write: bool) -> Result<Self, XEngineError> {
+        return spawn_blocking(move || {
+            return Ok(Self::new(SharedVolume::open_existing(path, write)?));
+        })
+        .await;
+    }
+
+    pub async fn open_existing_uncached(path: String, write: bool) -> Result<Self, XEngineError> {
+        return spawn_blocking(move || {
+            return Ok(Self::new(SharedVolume::open_existing_uncached(path, write)?));
+        })
+        .await;
+    }
+
+    pub fn shared(&self) -> &Arc<SharedVolume> {
+        return &self.shared;
+    }
+
+    pub async fn read_headers(&self, cached: bool) -> Result<(), XEngineError> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || {
+            // The cloned handle shares the open file description, and with it the lock
+            let mut file = match shared.file().try_clone() {
+                Ok(file) => file,
+                Err(err) => return Err(XEngineError::IO(err)),
+            };
+
+            return shared.write().read_headers(&mut file, cached);
+        })
+        .await;
+    }
+
+    pub fn get_actual_size(&self) -> u64 {
+        return self.shared.get_actual_size();
+    }
+
+    pub fn get_max_size(&self) -> u64 {
+        return self.shared.get_max_size();
+    }
+}
+
+impl AsyncChunksHandler for AsyncVolume {
+    fn get_chunk(&self, uuid: String) -> impl Future<Output = Result<Option<Chunk>, XEngineError>> + Send + use<> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || shared.get_chunk(uuid));
+    }
+
+    fn add_chunk(&self, chunk: Chunk) -> impl Future<Output = Result<Option<String>, XEngineError>> + Send + use<> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || shared.add_chunk(chunk));
+    }
+
+    fn add_chunks(&self, chunks: Vec<Chunk>) -> impl Future<Output = Result<(), XEngineError>> + Send + use<> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || shared.add_chunks(&chunks));
+    }
+
+    fn remove_chunk(&self, uuid: String) -> impl Future<Output = Result<Option<String>, XEngineError>> + Send + use<> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || shared.remove_chunk(uuid));
+    }
+}
+
+fn read_file_chunks(shared: &SharedVolume, query: XFileQuery) -> Result<Option<XFileChunks>, XEngineError> {
+    let chunk_uids: Vec<String> = (0..query.chunk_count)
+        .map(|index| match query.chunk_uids.get(index) {
+            Some(chunk_uid) => chunk_uid.clone(),
+            None => XFile::build_chunk_uid(query.uid.clone(), index),
+        })
+        .collect();
+
+    let chunks: XFileChunks = shared.get_chunks(&chunk_uids)?.into_iter().flatten().collect();
+
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+    return Ok(Some(chunks));
+}
+
+impl AsyncXFileHandler for AsyncVolume {
+    fn find_file_chunks(&self, query: XFileQuery) -> impl Future<Output = Result<Option<XFileChunks>, XEngineError>> + Send + use<> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || read_file_chunks(&shared, query));
+    }
+
+    // Splits the file on disk and stores its chunks in the volume
+    fn import_file(&self, user_uid: Uuid, path: PathBuf, vfolder: String) -> impl Future<Output = Result<XFile, XEngineError>> + Send + use<> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || {
+            // Chunks never exceed the chunk size of the volume
+            let chunk_size = shared.read().chunk_size as usize;
+
+            let file = match XFile::new_with_chunk_size(user_uid, &path, vfolder, chunk_size) {
+                Ok(file) => file,
+                Err(err) => return Err(XEngineError::IO(err)),
+            };
+
+            shared.add_chunks(&file.chunks)?;
+
+            return Ok(file);
+        });
+    }
+
+    // Rebuilds the file from the chunks stored in the volume, every chunk must be present
+    fn export_file(&self, mut file: XFile, path: PathBuf) -> impl Future<Output = Result<(), XEngineError>> + Send + use<> {
+        let shared = self.shared.clone();
+
+        return spawn_blocking(move || {
+            let chunk_uids: Vec<String> = (0..file.chunk_uids.len()).map(|index| file.get_chunk_uid(index)).collect();
+            let stored = shared.get_chunks(&chunk_uids)?;
+
+            let mut chunks: XFileChunks = Vec::new();
+            let mut remaining = file.size;
+
+            for (chunk_uid, chunk) in chunk_uids.into_iter().zip(stored) {
+                match chunk {
+                    Some(mut chunk) => {
+                        // Fixed size chunks are stored padded, the file size trims the last one
+                        let length = chunk.data.len().min(remaining);
+                        remaining -= length;
+
+                        chunk.length = Some(length);
+                        chunks.push(chunk);
+                    }
+                    None => return Err(XEngineError::ChunkNotFound(chunk_uid)),
+                }
+            }
+
+            file.chunks = chunks;
+
+            return file.export_path(&path);
+        });
+    }
+}
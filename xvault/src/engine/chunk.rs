@@ -25,10 +25,17 @@ pub use std::{
 };
 pub use uuid::Uuid;
 
-use crate::engine::error::XEngineError;
+use crate::engine::{crypto::ChunkKey, error::XEngineError};
 
 pub const CHUNK_SIZE: usize = 4096;
 
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+pub fn is_valid_chunk_size(chunk_size: usize) -> bool {
+    return chunk_size.is_power_of_two() && (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size);
+}
+
 #[derive(Serialize, Deserialize, Encode, Clone)]
 pub struct Chunk {
     pub uid: String,
@@ -36,6 +43,33 @@ pub struct Chunk {
     pub length: Option<usize>
 }
 
+impl Chunk {
+    // Chunk bytes without the padding of a last fixed size chunk
+    pub fn content(&self) -> &[u8] {
+        if let Some(length) = self.length {
+            return &self.data[..length];
+        }
+        return &self.data;
+    }
+
+    // Content addressed uid: the first 16 bytes of the BLAKE3 hash of the chunk content
+    pub fn build_content_uid(data: &[u8]) -> String {
+        let hash = blake3::hash(data);
+        let bytes: [u8; 16] = hash.as_bytes()[..16].try_into().unwrap();
+
+        return Uuid::new_v8(bytes).to_string();
+    }
+
+    // Content addressed uid for an encrypted volume, a hash keyed with its data key
+    pub fn build_keyed_content_uid(key: &ChunkKey, data: &[u8]) -> String {
+        return Uuid::new_v8(key.content_hash(data)).to_string();
+    }
+
+    pub fn is_content_addressed(&self) -> bool {
+        return self.uid == Chunk::build_content_uid(self.content());
+    }
+}
+
 impl Debug for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Chunk {{ uid: {}, length: {:?} }}", self.uid, self.length)
@@ -58,6 +92,7 @@ pub trait ChunksHandler {
 
     fn get_chunk_v2(&mut self, file: &File, uuid: String) -> Result<Option<Chunk>, XEngineError>;
     fn add_chunk_v2(&mut self, file: &File, chunk: Chunk) -> Result<Option<String>, XEngineError>;
+    fn remove_chunk_v2(&mut self, file: &File, uuid: String) -> Result<Option<String>, XEngineError>;
 
     fn add_chunks_v2(&mut self, file: &File, chunks: &Vec<Chunk>) -> Result<(), XEngineError> {
         let max_size = self.get_max_size() as usize;
@@ -65,7 +100,9 @@ pub trait ChunksHandler {
         
         let chunks_count = chunks.len();
 
-        assert!(actual_size + chunks_count <= max_size, "Can't add chunks to handler: actual_size + chunks_count > max_size ({} + {} > {})", actual_size, chunks_count, max_size);
+        if actual_size + chunks_count > max_size {
+            return Err(XEngineError::VolumeFull);
+        }
 
         for chunk in chunks.clone() {
             self.add_chunk_v2(file, chunk)?;
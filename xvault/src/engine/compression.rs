@@ -0,0 +1,129 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+pub use serde::{Deserialize, Serialize};
+
+use crate::engine::error::XEngineError;
+
+/*
+    Codec of a stored chunk, recorded in its offset map entry. Codecs are only
+    available with their cargo feature, a volume holding chunks of a disabled
+    codec still opens but those chunks cannot be read
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ChunkCodec {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl ChunkCodec {
+    pub fn from_number(value: u64) -> Result<Self, XEngineError> {
+        match value {
+            0 => return Ok(ChunkCodec::None),
+            1 => return Ok(ChunkCodec::Zstd),
+            2 => return Ok(ChunkCodec::Lz4),
+            _ => return Err(XEngineError::UnsupportedCodec(value)),
+        }
+    }
+
+    pub fn to_number(&self) -> u64 {
+        match self {
+            ChunkCodec::None => return 0,
+            ChunkCodec::Zstd => return 1,
+            ChunkCodec::Lz4 => return 2,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        match self {
+            ChunkCodec::None => return true,
+            ChunkCodec::Zstd => return cfg!(feature = "zstd"),
+            ChunkCodec::Lz4 => return cfg!(feature = "lz4"),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, XEngineError> {
+        match self {
+            ChunkCodec::None => return Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            ChunkCodec::Zstd => {
+                let compressed = zstd::bulk::compress(data, ZSTD_LEVEL);
+
+                if let Err(err) = compressed {
+                    return Err(XEngineError::IO(err));
+                }
+
+                return Ok(compressed.unwrap());
+            }
+            #[cfg(feature = "lz4")]
+            ChunkCodec::Lz4 => return Ok(lz4_flex::block::compress(data)),
+            #[allow(unreachable_patterns)]
+            _ => return Err(XEngineError::UnsupportedCodec(self.to_number())),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8], raw_len: usize) -> Result<Vec<u8>, XEngineError> {
+        match self {
+            ChunkCodec::None => return Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            ChunkCodec::Zstd => {
+                let raw = zstd::bulk::decompress(data, raw_len);
+
+                if let Err(err) = raw {
+                    return Err(XEngineError::IO(err));
+                }
+
+                return Ok(raw.unwrap());
+            }
+            #[cfg(feature = "lz4")]
+            ChunkCodec::Lz4 => {
+                let raw = lz4_flex::block::decompress(data, raw_len);
+
+                if let Err(err) = raw {
+                    return Err(XEngineError::IO(std::io::Error::new(std::io::ErrorKind::InvalidData, err)));
+                }
+
+                return Ok(raw.unwrap());
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = raw_len;
+                return Err(XEngineError::UnsupportedCodec(self.to_number()));
+            }
+        }
+    }
+}
+
+// Compresses data with the codec, falling back to the raw bytes when compression does not shrink them
+pub fn compress_chunk(codec: ChunkCodec, data: &[u8]) -> Result<(ChunkCodec, Vec<u8>), XEngineError> {
+    if codec == ChunkCodec::None {
+        return Ok((ChunkCodec::None, data.to_vec()));
+    }
+
+    let compressed = codec.compress(data)?;
+
+    if compressed.len() >= data.len() {
+        return Ok((ChunkCodec::None, data.to_vec()));
+    }
+
+    return Ok((codec, compressed));
+}
@@ -0,0 +1,294 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+pub use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use uuid::Uuid;
+
+#[cfg(feature = "encryption")]
+use argon2::Argon2;
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+
+use crate::engine::error::XEngineError;
+
+/*
+    Encrypted chunk: nonce | ciphertext | tag
+
+    The nonce is a keyed hash of the chunk uid and data, so the same chunk always
+    encrypts to the same bytes and stays deduplicated, while different data never
+    shares a nonce. The chunk uid is the associated data, so an entry cannot be
+    pointed to the ciphertext of another chunk.
+*/
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+pub const SALT_LEN: usize = 16;
+pub const WRAPPED_KEY_LEN: usize = NONCE_LEN + KEY_LEN + TAG_LEN;
+
+const CHUNK_KEY_CONTEXT: &str = "xvault 2025-06 chunk encryption key";
+const CHUNK_NONCE_CONTEXT: &str = "xvault 2025-06 chunk encryption nonce";
+const CHUNK_CONTENT_UID_CONTEXT: &str = "xvault 2025-06 content addressed chunk uid";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ChunkCipher {
+    #[default]
+    None,
+    ChaCha20Poly1305,
+}
+
+impl ChunkCipher {
+    pub fn from_number(value: u64) -> Result<Self, XEngineError> {
+        match value {
+            0 => return Ok(ChunkCipher::None),
+            1 => return Ok(ChunkCipher::ChaCha20Poly1305),
+            _ => return Err(XEngineError::UnsupportedCipher(value)),
+        }
+    }
+
+    pub fn to_number(&self) -> u64 {
+        match self {
+            ChunkCipher::None => return 0,
+            ChunkCipher::ChaCha20Poly1305 => return 1,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        match self {
+            ChunkCipher::None => return true,
+            ChunkCipher::ChaCha20Poly1305 => return cfg!(feature = "encryption"),
+        }
+    }
+}
+
+// Key of the chunks data, never serialized nor printed
+#[derive(Clone, PartialEq)]
+pub struct ChunkKey {
+    key: [u8; KEY_LEN],
+}
+
+impl Debug for ChunkKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ChunkKey {{ .. }}")
+    }
+}
+
+impl ChunkKey {
+    pub fn from_bytes(key: [u8; KEY_LEN]) -> Self {
+        return Self { key };
+    }
+
+    // Per user key, from the user uid namespace and a secret
+    pub fn derive(user_uid: &Uuid, secret: &[u8]) -> Self {
+        let mut material = Vec::with_capacity(16 + secret.len());
+        material.extend_from_slice(user_uid.as_bytes());
+        material.extend_from_slice(secret);
+
+        return Self {
+            key: blake3::derive_key(CHUNK_KEY_CONTEXT, &material),
+        };
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        return &self.key;
+    }
+
+    #[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+    fn nonce(&self, chunk_uid: &str, data: &[u8]) -> [u8; NONCE_LEN] {
+        let nonce_key = blake3::derive_key(CHUNK_NONCE_CONTEXT, &self.key);
+
+        let mut hasher = blake3::Hasher::new_keyed(&nonce_key);
+        hasher.update(chunk_uid.as_bytes());
+        hasher.update(data);
+
+        return hasher.finalize().as_bytes()[..NONCE_LEN].try_into().unwrap();
+    }
+
+    // Keyed hash of the chunk content, so its uid only matches known data for the key holder
+    pub fn content_hash(&self, data: &[u8]) -> [u8; 16] {
+        let uid_key = blake3::derive_key(CHUNK_CONTENT_UID_CONTEXT, &self.key);
+
+        return blake3::keyed_hash(&uid_key, data).as_bytes()[..16].try_into().unwrap();
+    }
+}
+
+/*
+    Key slots of an encrypted volume, stored in the superblock.
+
+    The data key encrypting the chunks is wrapped by a master key derived from a
+    passphrase with Argon2id and the salt. While a data key rotation is running the
+    previous data key is kept too, so chunks not yet re-encrypted stay readable.
+    An all zero slot is empty.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VolumeKeySlots {
+    pub salt: [u8; SALT_LEN],
+    pub current: [u8; WRAPPED_KEY_LEN],
+    pub previous: [u8; WRAPPED_KEY_LEN],
+}
+
+impl Default for VolumeKeySlots {
+    fn default() -> Self {
+        Self {
+            salt: [0u8; SALT_LEN],
+            current: [0u8; WRAPPED_KEY_LEN],
+            previous: [0u8; WRAPPED_KEY_LEN],
+        }
+    }
+}
+
+impl VolumeKeySlots {
+    pub fn is_encrypted(&self) -> bool {
+        return self.current != [0u8; WRAPPED_KEY_LEN];
+    }
+
+    pub fn has_previous(&self) -> bool {
+        return self.previous != [0u8; WRAPPED_KEY_LEN];
+    }
+}
+
+#[cfg(feature = "encryption")]
+pub fn generate_key() -> ChunkKey {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+
+    return ChunkKey::from_bytes(key);
+}
+
+#[cfg(feature = "encryption")]
+pub fn generate_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    return salt;
+}
+
+#[cfg(feature = "encryption")]
+pub fn derive_master_key(passphrase: &[u8], salt: &[u8; SALT_LEN]) -> Result<ChunkKey, XEngineError> {
+    let mut key = [0u8; KEY_LEN];
+
+    if let Err(err) = Argon2::default().hash_password_into(passphrase, salt, &mut key) {
+        return Err(XEngineError::IO(std::io::Error::other(err.to_string())));
+    }
+
+    return Ok(ChunkKey::from_bytes(key));
+}
+
+// The wrapped key is bound to the volume uid, so key slots cannot be moved between volumes
+#[cfg(feature = "encryption")]
+pub fn wrap_key(master: &ChunkKey, key: &ChunkKey, volume_uid: &str) -> Result<[u8; WRAPPED_KEY_LEN], XEngineError> {
+    let cipher = ChaCha20Poly1305::new(master.as_bytes().into());
+
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let payload = Payload {
+        msg: key.as_bytes(),
+        aad: volume_uid.as_bytes(),
+    };
+
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), payload);
+
+    if let Err(err) = ciphertext {
+        return Err(XEngineError::IO(std::io::Error::other(err.to_string())));
+    }
+
+    let mut wrapped = [0u8; WRAPPED_KEY_LEN];
+    wrapped[..NONCE_LEN].copy_from_slice(&nonce);
+    wrapped[NONCE_LEN..].copy_from_slice(&ciphertext.unwrap());
+
+    return Ok(wrapped);
+}
+
+#[cfg(feature = "encryption")]
+pub fn unwrap_key(master: &ChunkKey, wrapped: &[u8; WRAPPED_KEY_LEN], volume_uid: &str) -> Result<ChunkKey, XEngineError> {
+    let cipher = ChaCha20Poly1305::new(master.as_bytes().into());
+    let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+
+    let payload = Payload {
+        msg: ciphertext,
+        aad: volume_uid.as_bytes(),
+    };
+
+    let Ok(key) = cipher.decrypt(Nonce::from_slice(nonce), payload) else {
+        return Err(XEngineError::WrongKey);
+    };
+
+    return Ok(ChunkKey::from_bytes(key.try_into().unwrap()));
+}
+
+#[cfg(feature = "encryption")]
+pub fn encrypt_chunk(key: &ChunkKey, chunk_uid: &str, data: &[u8]) -> Result<Vec<u8>, XEngineError> {
+    let cipher = ChaCha20Poly1305::new(key.as_bytes().into());
+    let nonce = key.nonce(chunk_uid, data);
+
+    let payload = Payload {
+        msg: data,
+        aad: chunk_uid.as_bytes(),
+    };
+
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), payload);
+
+    if let Err(err) = ciphertext {
+        return Err(XEngineError::IO(std::io::Error::other(err.to_string())));
+    }
+
+    let ciphertext = ciphertext.unwrap();
+
+    let mut buf = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    buf.extend_from_slice(&nonce);
+    buf.extend_from_slice(&ciphertext);
+
+    return Ok(buf);
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn encrypt_chunk(key: &ChunkKey, chunk_uid: &str, data: &[u8]) -> Result<Vec<u8>, XEngineError> {
+    let _ = (key, chunk_uid, data);
+    return Err(XEngineError::UnsupportedCipher(ChunkCipher::ChaCha20Poly1305.to_number()));
+}
+
+#[cfg(feature = "encryption")]
+pub fn decrypt_chunk(key: &ChunkKey, chunk_uid: &str, stored: &[u8]) -> Result<Vec<u8>, XEngineError> {
+    if stored.len() < NONCE_LEN + TAG_LEN {
+        return Err(XEngineError::DecryptionFailed(chunk_uid.to_string()));
+    }
+
+    let cipher = ChaCha20Poly1305::new(key.as_bytes().into());
+    let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+
+    let payload = Payload {
+        msg: ciphertext,
+        aad: chunk_uid.as_bytes(),
+    };
+
+    // A wrong key and a tampered chunk both fail the tag
+    let Ok(data) = cipher.decrypt(Nonce::from_slice(nonce), payload) else {
+        return Err(XEngineError::DecryptionFailed(chunk_uid.to_string()));
+    };
+
+    return Ok(data);
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn decrypt_chunk(key: &ChunkKey, chunk_uid: &str, stored: &[u8]) -> Result<Vec<u8>, XEngineError> {
+    let _ = (key, chunk_uid, stored);
+    return Err(XEngineError::UnsupportedCipher(ChunkCipher::ChaCha20Poly1305.to_number()));
+}
@@ -52,6 +52,14 @@ impl Device {
     pub fn add_volume(&mut self, volume: Volume) {
         self.volumes.insert(volume.uid.clone(), volume);
     }
+
+    // The volume a file handle belongs to, matched on the uid in its superblock.
+    // Every v2 call on a file of no volume of the device fails with VolumeNotFound
+    fn get_volume_for_file(&mut self, file: &File) -> Result<Option<&mut Volume>, XEngineError> {
+        let volume_uid = Volume::new().read_uid_from_file(file)?;
+
+        return Ok(self.volumes.get_mut(&volume_uid));
+    }
 }
 
 impl ChunksHandler for Device {
@@ -90,14 +98,33 @@ impl ChunksHandler for Device {
     }
     
     fn get_chunk_v2(&mut self, file: &File, uuid: String) -> Result<Option<Chunk>, XEngineError> {
-        todo!()
+        match self.get_volume_for_file(file)? {
+            Some(volume) => return volume.get_chunk_v2(file, uuid),
+            None => return Err(XEngineError::VolumeNotFound),
+        }
     }
     
     fn add_chunk_v2(&mut self, file: &File, chunk: Chunk) -> Result<Option<String>, XEngineError> {
-        todo!()
+        match self.get_volume_for_file(file)? {
+            Some(volume) => return volume.add_chunk_v2(file, chunk),
+            None => return Err(XEngineError::VolumeNotFound),
+        }
+    }
+
+    fn remove_chunk_v2(&mut self, file: &File, uuid: String) -> Result<Option<String>, XEngineError> {
+        match self.get_volume_for_file(file)? {
+            Some(volume) => return volume.remove_chunk_v2(file, uuid),
+            None => return Err(XEngineError::VolumeNotFound),
+        }
+    }
+
+    // The owning volume checks capacity per chunk, so chunks already stored do not count
+    fn add_chunks_v2(&mut self, file: &File, chunks: &Vec<Chunk>) -> Result<(), XEngineError> {
+        match self.get_volume_for_file(file)? {
+            Some(volume) => return volume.add_chunks_v2(file, chunks),
+            None => return Err(XEngineError::VolumeNotFound),
+        }
     }
-    
-    
 }
 
 impl XFileHandler for Device {
@@ -107,7 +134,11 @@ impl XFileHandler for Device {
         let mut chunks: Vec<Chunk> = Vec::new();
 
         for index in  0..count {
-            let chunk_uid = XFile::build_chunk_uid(file_uid.clone(), index);
+            // Ordered chunk uids from the file take precedence over the index based ones
+            let chunk_uid = match query.chunk_uids.get(index) {
+                Some(chunk_uid) => chunk_uid.clone(),
+                None => XFile::build_chunk_uid(file_uid.clone(), index),
+            };
             if let Some(chunk) = self.get_chunk(chunk_uid) {
                 chunks.push(chunk.clone());
             }
@@ -21,7 +21,37 @@ use std::io;
 pub enum XEngineError {
     FileNotExists,
     VolumeAlreadyAllocated,
+    VolumeNotFound,
     InvalidUuid,
+    VolumeFull,
+    VolumeFragmented,
+    CannotShrinkVolume,
+    CompactionInProgress,
+    InvalidChunkSize(u64),
+    InvalidVolumeLayout,
+    NotAVolume,
+    CorruptHeader,
+    UnsupportedVersion(u64),
+    UnsupportedFeatures(u64),
+    ChunkOutOfBounds(String),
+    ChunkNotFound(String),
+    ChecksumMismatch { chunk_uid: String, volume_uid: String },
+    VolumeNotMapped,
+    VolumeLocked,
+    JournalPending,
+    GroupCommitWithoutWorker,
+    UnsupportedCodec(u64),
+    UnsupportedCipher(u64),
+    EncryptionKeyRequired,
+    DecryptionFailed(String),
+    WrongKey,
+    VolumeNotEncrypted,
+    VolumeAlreadyEncrypted,
+    VolumeNotEmpty,
+    UnkeyedContentUid(String),
+    TaskFailed(String),
+    QueueFull,
+    QueueClosed,
     IO(io::Error),
     Encode(bincode::error::EncodeError),
     Decode(bincode::error::DecodeError),
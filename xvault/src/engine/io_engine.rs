@@ -0,0 +1,279 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+pub use serde::{Deserialize, Serialize};
+use std::{fs::File, os::unix::fs::FileExt};
+
+use crate::engine::{error::XEngineError, journal::JournalRecord};
+
+/*
+    Engine used for batched chunk reads and writes. IoUring submits a whole batch
+    to the kernel at once through a ring kept by each thread, and needs the io-uring
+    cargo feature on Linux, without it or when the kernel refuses to set up a ring
+    the positional syscalls are used
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum IoEngine {
+    #[default]
+    Sync,
+    IoUring,
+}
+
+impl IoEngine {
+    pub fn is_available(&self) -> bool {
+        match self {
+            IoEngine::Sync => return true,
+            IoEngine::IoUring => return cfg!(all(feature = "io-uring", target_os = "linux")),
+        }
+    }
+
+    // Engine the batches of the calling thread go through, Sync when IoUring has no ring
+    pub fn active(&self) -> IoEngine {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if *self == IoEngine::IoUring && uring::has_ring() {
+            return IoEngine::IoUring;
+        }
+
+        return IoEngine::Sync;
+    }
+}
+
+pub struct ReadRequest {
+    pub offset: u64,
+    pub buf: Vec<u8>,
+}
+
+// Merges writes where one ends exactly where the next starts, keeping their order
+pub fn coalesce_writes(writes: Vec<JournalRecord>) -> Vec<JournalRecord> {
+    let mut coalesced: Vec<JournalRecord> = Vec::with_capacity(writes.len());
+
+    for write in writes {
+        match coalesced.last_mut() {
+            Some(last) if last.offset + last.data.len() as u64 == write.offset => {
+                last.data.extend_from_slice(&write.data);
+            }
+            _ => coalesced.push(write),
+        }
+    }
+
+    return coalesced;
+}
+
+pub fn write_batch(engine: IoEngine, file: &File, writes: &[JournalRecord]) -> Result<(), XEngineError> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if engine == IoEngine::IoUring && uring::write_batch(file, writes)? {
+        return Ok(());
+    }
+    let _ = engine;
+
+    for write in writes {
+        if let Err(err) = file.write_all_at(&write.data, write.offset) {
+            return Err(XEngineError::IO(err));
+        }
+    }
+
+    return Ok(());
+}
+
+pub fn read_batch(engine: IoEngine, file: &File, reads: &mut [ReadRequest]) -> Result<(), XEngineError> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if engine == IoEngine::IoUring && uring::read_batch(file, reads)? {
+        return Ok(());
+    }
+    let _ = engine;
+
+    for read in reads.iter_mut() {
+        if let Err(err) = file.read_exact_at(&mut read.buf, read.offset) {
+            return Err(XEngineError::IO(err));
+        }
+    }
+
+    return Ok(());
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring {
+    use std::{cell::RefCell, fs::File, io, os::{fd::AsRawFd, unix::fs::FileExt}};
+
+    use io_uring::{opcode, squeue, types, IoUring};
+
+    use crate::engine::{error::XEngineError, io_engine::ReadRequest, journal::JournalRecord};
+
+    const QUEUE_DEPTH: usize = 64;
+
+    thread_local! {
+        // Set up by the first batch of a thread and reused by the next ones
+        static RING: RefCell<Option<IoUring>> = RefCell::new(setup());
+    }
+
+    // None when the kernel has no io_uring support or it is disabled for the process
+    fn setup() -> Option<IoUring> {
+        return IoUring::new(QUEUE_DEPTH as u32).ok();
+    }
+
+    pub fn has_ring() -> bool {
+        return RING.with(|ring| ring.borrow().is_some());
+    }
+
+    // Runs op on the ring of the calling thread, None without a ring
+    fn with_ring<T, F>(op: F) -> Result<Option<T>, XEngineError>
+    where
+        F: FnOnce(&mut IoUring) -> Result<T, XEngineError>,
+    {
+        return RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+
+            let Some(active) = ring.as_mut() else {
+                return Ok(None);
+            };
+
+            let result = op(active);
+
+            // A failed batch may leave entries never submitted, they are dropped with the ring
+            if result.is_err() {
+                *ring = setup();
+            }
+
+            return result.map(Some);
+        });
+    }
+
+    // Submits the entries and returns the bytes transferred by each of them
+    fn submit(ring: &mut IoUring, entries: &[squeue::Entry]) -> Result<Vec<usize>, XEngineError> {
+        let mut done = vec![0usize; entries.len()];
+
+        for entry in entries {
+            // The batch never exceeds the queue depth, so the queue has room
+            if unsafe { ring.submission().push(entry) }.is_err() {
+                return Err(XEngineError::IO(io::Error::other("io_uring submission queue full")));
+            }
+        }
+
+        let mut completed = 0;
+        let mut error = None;
+
+        // The kernel uses the buffers of a submitted entry until it completes,
+        // so an error is only returned once no entry is in flight
+        while completed < entries.len() {
+            let waited = ring.submit_and_wait(entries.len() - completed);
+
+            for cqe in ring.completion() {
+                completed += 1;
+
+                if cqe.result() < 0 {
+                    error.get_or_insert(io::Error::from_raw_os_error(-cqe.result()));
+                } else {
+                    done[cqe.user_data() as usize] = cqe.result() as usize;
+                }
+            }
+
+            if let Err(err) = waited
+                && err.kind() != io::ErrorKind::Interrupted
+            {
+                let unsubmitted = ring.submission().len();
+
+                if completed + unsubmitted == entries.len() {
+                    error.get_or_insert(err);
+                    break;
+                }
+            }
+        }
+
+        if let Some(err) = error {
+            return Err(XEngineError::IO(err));
+        }
+
+        return Ok(done);
+    }
+
+    pub fn write_batch(file: &File, writes: &[JournalRecord]) -> Result<bool, XEngineError> {
+        let written = with_ring(|ring| {
+            return write_batch_with(ring, file, writes);
+        })?;
+
+        return Ok(written.is_some());
+    }
+
+    fn write_batch_with(ring: &mut IoUring, file: &File, writes: &[JournalRecord]) -> Result<(), XEngineError> {
+        let fd = types::Fd(file.as_raw_fd());
+
+        for batch in writes.chunks(QUEUE_DEPTH) {
+            let entries: Vec<squeue::Entry> = batch
+                .iter()
+                .enumerate()
+                .map(|(index, write)| {
+                    opcode::Write::new(fd, write.data.as_ptr(), write.data.len() as u32)
+                        .offset(write.offset)
+                        .build()
+                        .user_data(index as u64)
+                })
+                .collect();
+
+            let done = submit(ring, &entries)?;
+
+            // Short writes are completed with positional writes
+            for (write, written) in batch.iter().zip(done) {
+                if written < write.data.len()
+                    && let Err(err) = file.write_all_at(&write.data[written..], write.offset + written as u64)
+                {
+                    return Err(XEngineError::IO(err));
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub fn read_batch(file: &File, reads: &mut [ReadRequest]) -> Result<bool, XEngineError> {
+        let filled = with_ring(|ring| {
+            return read_batch_with(ring, file, reads);
+        })?;
+
+        return Ok(filled.is_some());
+    }
+
+    fn read_batch_with(ring: &mut IoUring, file: &File, reads: &mut [ReadRequest]) -> Result<(), XEngineError> {
+        let fd = types::Fd(file.as_raw_fd());
+
+        for batch in reads.chunks_mut(QUEUE_DEPTH) {
+            let entries: Vec<squeue::Entry> = batch
+                .iter_mut()
+                .enumerate()
+                .map(|(index, read)| {
+                    opcode::Read::new(fd, read.buf.as_mut_ptr(), read.buf.len() as u32)
+                        .offset(read.offset)
+                        .build()
+                        .user_data(index as u64)
+                })
+                .collect();
+
+            let done = submit(ring, &entries)?;
+
+            for (read, filled) in batch.iter_mut().zip(done) {
+                let offset = read.offset + filled as u64;
+
+                if filled < read.buf.len()
+                    && let Err(err) = file.read_exact_at(&mut read.buf[filled..], offset)
+                {
+                    return Err(XEngineError::IO(err));
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
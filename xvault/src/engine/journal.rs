@@ -0,0 +1,298 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+pub use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use crate::engine::{
+    error::XEngineError,
+    utils::{compute_checksum, decode_number, encode_number, get_bincode_config},
+};
+
+/*
+    Journal file, next to the volume file:
+    magic | frames, each one records count | records (offset, len, bytes) | checksum
+
+    A journal is written and synced before its records are applied to the volume,
+    and removed once the volume is synced. Unsynced commits append their own frame.
+    Frames are replayed in order up to the first torn one, which fails the checksum
+    and is discarded with the ones after it, since none of them reached the volume.
+*/
+pub const JOURNAL_MAGIC: [u8; 8] = *b"XVJOURNL";
+
+const JOURNAL_MAGIC_LEN: usize = 8;
+const JOURNAL_NUMBER_LEN: usize = 8; //u64 size
+
+/*
+    When committed transactions reach the disk.
+
+    PerChunk syncs the data, the journal and the volume before every mutation returns.
+    The other levels keep the committed metadata out of the volume file until the
+    next sync and only append to the journal, which the page cache keeps across a
+    crashed process, so a power loss loses the writes since the last sync but never
+    leaves half applied metadata:
+    OnHeaderFlush syncs on write_headers and flush, GroupCommit once a group holds
+    the given chunks or is older than the given interval, None never.
+    Pending metadata grown too large is synced whatever the level. GroupCommit
+    relies on a queue worker to sync a group on time, a volume without one
+    refuses writes with GroupCommitWithoutWorker
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Durability {
+    None,
+    OnHeaderFlush,
+    #[default]
+    PerChunk,
+    GroupCommit { interval_ms: u64, chunks: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JournalRecord {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+pub fn journal_path(volume_path: &str) -> String {
+    return format!("{volume_path}.journal");
+}
+
+pub fn encode_journal(records: &[JournalRecord]) -> Result<Vec<u8>, XEngineError> {
+    let mut buf = JOURNAL_MAGIC.to_vec();
+    buf.extend_from_slice(&encode_journal_frame(records)?);
+
+    return Ok(buf);
+}
+
+pub fn encode_journal_frame(records: &[JournalRecord]) -> Result<Vec<u8>, XEngineError> {
+    let config = get_bincode_config();
+    let mut buf = Vec::new();
+
+    buf.extend_from_slice(&encode_number(records.len() as u64, config)?);
+
+    for record in records {
+        buf.extend_from_slice(&encode_number(record.offset, config)?);
+        buf.extend_from_slice(&encode_number(record.data.len() as u64, config)?);
+        buf.extend_from_slice(&record.data);
+    }
+
+    let checksum = compute_checksum(&buf);
+    buf.extend_from_slice(&encode_number(checksum, config)?);
+
+    return Ok(buf);
+}
+
+pub fn decode_journal(buf: &[u8]) -> Result<Option<Vec<JournalRecord>>, XEngineError> {
+    if buf.len() < JOURNAL_MAGIC_LEN || buf[..JOURNAL_MAGIC_LEN] != JOURNAL_MAGIC {
+        return Ok(None);
+    }
+
+    let mut index = JOURNAL_MAGIC_LEN;
+    let mut records = Vec::new();
+
+    while let Some((frame, frame_len)) = decode_journal_frame(&buf[index..])? {
+        records.extend(frame);
+        index += frame_len;
+    }
+
+    if records.is_empty() {
+        return Ok(None);
+    }
+
+    return Ok(Some(records));
+}
+
+// Decodes the frame at the start of buf and its length, None if it is torn
+fn decode_journal_frame(buf: &[u8]) -> Result<Option<(Vec<JournalRecord>, usize)>, XEngineError> {
+    let config = get_bincode_config();
+
+    if buf.len() < 2 * JOURNAL_NUMBER_LEN {
+        return Ok(None);
+    }
+
+    let mut index = 0;
+    let count = decode_number(&buf[index..index + JOURNAL_NUMBER_LEN], &config)?;
+    index += JOURNAL_NUMBER_LEN;
+
+    let mut records = Vec::new();
+
+    for _ in 0..count {
+        if index + 2 * JOURNAL_NUMBER_LEN > buf.len() {
+            return Ok(None);
+        }
+
+        let offset = decode_number(&buf[index..index + JOURNAL_NUMBER_LEN], &config)?;
+        index += JOURNAL_NUMBER_LEN;
+
+        let len = decode_number(&buf[index..index + JOURNAL_NUMBER_LEN], &config)? as usize;
+        index += JOURNAL_NUMBER_LEN;
+
+        if len > buf.len() - index {
+            return Ok(None);
+        }
+
+        records.push(JournalRecord {
+            offset,
+            data: buf[index..index + len].to_vec(),
+        });
+        index += len;
+    }
+
+    if index + JOURNAL_NUMBER_LEN > buf.len() {
+        return Ok(None);
+    }
+
+    if decode_number(&buf[index..index + JOURNAL_NUMBER_LEN], &config)? != compute_checksum(&buf[..index]) {
+        return Ok(None);
+    }
+
+    return Ok(Some((records, index + JOURNAL_NUMBER_LEN)));
+}
+
+pub fn write_journal(path: &str, records: &[JournalRecord]) -> Result<(), XEngineError> {
+    return write_journal_with(path, records, true);
+}
+
+pub fn write_journal_with(path: &str, records: &[JournalRecord], sync: bool) -> Result<(), XEngineError> {
+    let buf = encode_journal(records)?;
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path);
+
+    if let Err(err) = file {
+        return Err(XEngineError::IO(err));
+    }
+
+    let file = file.unwrap();
+
+    if let Err(err) = file.write_all_at(&buf, 0) {
+        return Err(XEngineError::IO(err));
+    }
+
+    if sync && let Err(err) = file.sync_data() {
+        return Err(XEngineError::IO(err));
+    }
+
+    // A synced journal must not be lost with its directory entry
+    if sync {
+        return sync_parent_dir(path);
+    }
+
+    return Ok(());
+}
+
+// Appends an unsynced frame, the journal is created with its magic if missing
+pub fn append_journal(path: &str, records: &[JournalRecord]) -> Result<(), XEngineError> {
+    let file = OpenOptions::new().append(true).create(true).open(path);
+
+    if let Err(err) = file {
+        return Err(XEngineError::IO(err));
+    }
+
+    let mut file = file.unwrap();
+
+    let len = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(err) => return Err(XEngineError::IO(err)),
+    };
+
+    let buf = if len == 0 {
+        encode_journal(records)?
+    } else {
+        encode_journal_frame(records)?
+    };
+
+    if let Err(err) = file.write_all(&buf) {
+        return Err(XEngineError::IO(err));
+    }
+
+    return Ok(());
+}
+
+pub fn read_journal(path: &str) -> Result<Option<Vec<JournalRecord>>, XEngineError> {
+    let file = File::open(path);
+
+    if let Err(err) = &file
+        && err.kind() == io::ErrorKind::NotFound
+    {
+        return Ok(None);
+    }
+
+    let mut file = match file {
+        Ok(file) => file,
+        Err(err) => return Err(XEngineError::IO(err)),
+    };
+
+    let mut buf = Vec::new();
+    if let Err(err) = file.read_to_end(&mut buf) {
+        return Err(XEngineError::IO(err));
+    }
+
+    return decode_journal(&buf);
+}
+
+pub fn apply_journal(file: &File, records: &[JournalRecord]) -> Result<(), XEngineError> {
+    return apply_journal_with(file, records, true);
+}
+
+pub fn apply_journal_with(file: &File, records: &[JournalRecord], sync: bool) -> Result<(), XEngineError> {
+    for record in records {
+        if let Err(err) = file.write_all_at(&record.data, record.offset) {
+            return Err(XEngineError::IO(err));
+        }
+    }
+
+    if sync && let Err(err) = file.sync_data() {
+        return Err(XEngineError::IO(err));
+    }
+
+    return Ok(());
+}
+
+pub fn clear_journal(path: &str) -> Result<(), XEngineError> {
+    match fs::remove_file(path) {
+        // An applied journal must not come back and be replayed over newer metadata
+        Ok(()) => return sync_parent_dir(path),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(XEngineError::IO(err)),
+    }
+}
+
+fn sync_parent_dir(path: &str) -> Result<(), XEngineError> {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let dir = match File::open(dir) {
+        Ok(dir) => dir,
+        Err(err) => return Err(XEngineError::IO(err)),
+    };
+
+    if let Err(err) = dir.sync_all() {
+        return Err(XEngineError::IO(err));
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,92 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use memmap2::Mmap;
+use std::fs::File;
+
+use crate::engine::{error::XEngineError, volume::ChunkOffset};
+
+// Read only shared mapping of a whole volume file, writes done through the
+// file handle are visible in the mapping
+#[derive(Debug)]
+pub struct VolumeMap {
+    mmap: Mmap,
+}
+
+impl VolumeMap {
+    pub fn new(file: &File) -> Result<Self, XEngineError> {
+        // The mapping is owned by a single Volume (see VolumeMapping), which drops it
+        // before resize changes the file length. Other handles cannot truncate the
+        // file while it is open, the volume lock keeps them out
+        let mmap = unsafe { Mmap::map(file) };
+
+        if let Err(err) = mmap {
+            return Err(XEngineError::IO(err));
+        }
+
+        return Ok(Self {
+            mmap: mmap.unwrap(),
+        });
+    }
+
+    pub fn len(&self) -> u64 {
+        return self.mmap.len() as u64;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.mmap.is_empty();
+    }
+
+    pub fn slice(&self, offset: &ChunkOffset) -> Option<&[u8]> {
+        if offset.start > offset.end || offset.end > self.len() {
+            return None;
+        }
+
+        return Some(&self.mmap[offset.start as usize..offset.end as usize]);
+    }
+}
+
+// Mapping held by a Volume. A cloned volume starts unmapped, so a mapping is never
+// shared with a handle that could resize the file under it
+#[derive(Debug, Default)]
+pub struct VolumeMapping {
+    map: Option<VolumeMap>,
+}
+
+impl VolumeMapping {
+    pub fn get(&self) -> Option<&VolumeMap> {
+        return self.map.as_ref();
+    }
+
+    pub fn set(&mut self, map: VolumeMap) {
+        self.map = Some(map);
+    }
+
+    pub fn take(&mut self) -> Option<VolumeMap> {
+        return self.map.take();
+    }
+
+    pub fn is_some(&self) -> bool {
+        return self.map.is_some();
+    }
+}
+
+impl Clone for VolumeMapping {
+    fn clone(&self) -> Self {
+        return Self::default();
+    }
+}
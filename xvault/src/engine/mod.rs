@@ -20,4 +20,16 @@ pub mod volume;
 pub mod xfile;
 pub mod chunk;
 pub mod utils;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod journal;
+pub mod compression;
+pub mod crypto;
+pub mod shared;
+pub mod io_engine;
+pub mod queue;
+
+#[cfg(feature = "mmap")]
+pub mod mmap;
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
\ No newline at end of file
@@ -0,0 +1,455 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    io,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::engine::{
+    chunk::{Chunk, ChunksHandler},
+    error::XEngineError,
+    journal::Durability,
+    shared::SharedVolume,
+};
+
+/*
+    Per volume I/O queue.
+
+    Requests are handed to a worker thread through a bounded channel, so submitting
+    blocks (or fails with try_*) while the queue is full. The worker drains what is
+    queued into a batch: the writes of a batch are stored with one add_chunks_v2,
+    which flushes the offset table once and coalesces adjacent data writes, then its
+    reads are served with one read_chunks ordered by offset. A batch ends before a
+    write of a chunk read earlier in it, so every read sees the writes submitted
+    before it and none submitted after. Requests are completed once the volume lock
+    is released, so a callback may use the volume. With group commit a write is
+    completed once its group is synced, either by the write closing the group or
+    by the worker once the group interval expires.
+*/
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+type Callback<T> = Box<dyn FnOnce(Result<T, XEngineError>) + Send>;
+
+// Invoked exactly once, a request dropped by the worker completes with QueueClosed
+pub struct Completion<T> {
+    callback: Option<Callback<T>>,
+}
+
+impl<T> Completion<T> {
+    fn new(callback: Callback<T>) -> Self {
+        return Self {
+            callback: Some(callback),
+        };
+    }
+
+    fn complete(mut self, result: Result<T, XEngineError>) {
+        if let Some(callback) = self.callback.take() {
+            callback(result);
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(XEngineError::QueueClosed));
+        }
+    }
+}
+
+struct TicketState<T> {
+    result: Option<Result<T, XEngineError>>,
+    waker: Option<Waker>,
+}
+
+type TicketShared<T> = Arc<(Mutex<TicketState<T>>, Condvar)>;
+
+// Result of a queued request, waited on from a thread or awaited as a future
+pub struct QueueTicket<T> {
+    state: TicketShared<T>,
+}
+
+impl<T: Send + 'static> QueueTicket<T> {
+    fn new() -> (Self, Completion<T>) {
+        let state: TicketShared<T> = Arc::new((
+            Mutex::new(TicketState {
+                result: None,
+                waker: None,
+            }),
+            Condvar::new(),
+        ));
+        let shared = state.clone();
+
+        let completion = Completion::new(Box::new(move |result| {
+            let (lock, ready) = &*shared;
+            let mut state = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            ready.notify_all();
+        }));
+
+        return (Self { state }, completion);
+    }
+}
+
+impl<T> QueueTicket<T> {
+    pub fn is_done(&self) -> bool {
+        let (lock, _) = &*self.state;
+
+        return lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).result.is_some();
+    }
+
+    pub fn wait(self) -> Result<T, XEngineError> {
+        let (lock, ready) = &*self.state;
+        let mut state = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = ready.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+impl<T> Future for QueueTicket<T> {
+    type Output = Result<T, XEngineError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (lock, _) = &*self.state;
+        let mut state = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(result) = state.result.take() {
+            return Poll::Ready(result);
+        }
+
+        state.waker = Some(cx.waker().clone());
+
+        return Poll::Pending;
+    }
+}
+
+pub enum QueueRequest {
+    Write {
+        chunk: Chunk,
+        done: Completion<Option<String>>,
+    },
+    Read {
+        uid: String,
+        done: Completion<Option<Chunk>>,
+    },
+}
+
+#[derive(Debug)]
+pub struct VolumeQueue {
+    shared: Arc<SharedVolume>,
+    sender: Option<SyncSender<QueueRequest>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl VolumeQueue {
+    pub fn new(shared: Arc<SharedVolume>) -> Self {
+        return Self::with_capacity(shared, DEFAULT_QUEUE_CAPACITY);
+    }
+
+    // Capacity bounds both the pending requests and the size of a batch
+    pub fn with_capacity(shared: Arc<SharedVolume>, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+
+        // The worker syncs the open group once its interval expires
+        shared.write().set_group_timer(true);
+
+        let volume = shared.clone();
+        let worker = thread::spawn(move || run_worker(volume, receiver, capacity));
+
+        return Self {
+            shared,
+            sender: Some(sender),
+            worker: Some(worker),
+        };
+    }
+
+    pub fn shared(&self) -> &Arc<SharedVolume> {
+        return &self.shared;
+    }
+
+    pub fn write(&self, chunk: Chunk) -> Result<QueueTicket<Option<String>>, XEngineError> {
+        let (ticket, done) = QueueTicket::new();
+        self.submit(QueueRequest::Write { chunk, done })?;
+
+        return Ok(ticket);
+    }
+
+    pub fn read(&self, uid: String) -> Result<QueueTicket<Option<Chunk>>, XEngineError> {
+        let (ticket, done) = QueueTicket::new();
+        self.submit(QueueRequest::Read { uid, done })?;
+
+        return Ok(ticket);
+    }
+
+    pub fn try_write(&self, chunk: Chunk) -> Result<QueueTicket<Option<String>>, XEngineError> {
+        let (ticket, done) = QueueTicket::new();
+        self.try_submit(QueueRequest::Write { chunk, done })?;
+
+        return Ok(ticket);
+    }
+
+    pub fn try_read(&self, uid: String) -> Result<QueueTicket<Option<Chunk>>, XEngineError> {
+        let (ticket, done) = QueueTicket::new();
+        self.try_submit(QueueRequest::Read { uid, done })?;
+
+        return Ok(ticket);
+    }
+
+    // The callback runs on the worker thread once the chunk is stored
+    pub fn write_with<F>(&self, chunk: Chunk, callback: F) -> Result<(), XEngineError>
+    where
+        F: FnOnce(Result<Option<String>, XEngineError>) + Send + 'static,
+    {
+        let done = Completion::new(Box::new(callback));
+
+        return self.submit(QueueRequest::Write { chunk, done });
+    }
+
+    // The callback runs on the worker thread once the chunk is read
+    pub fn read_with<F>(&self, uid: String, callback: F) -> Result<(), XEngineError>
+    where
+        F: FnOnce(Result<Option<Chunk>, XEngineError>) + Send + 'static,
+    {
+        let done = Completion::new(Box::new(callback));
+
+        return self.submit(QueueRequest::Read { uid, done });
+    }
+
+    // Blocks while the queue is full
+    pub fn submit(&self, request: QueueRequest) -> Result<(), XEngineError> {
+        let Some(sender) = self.sender.as_ref() else {
+            return Err(XEngineError::QueueClosed);
+        };
+
+        if sender.send(request).is_err() {
+            return Err(XEngineError::QueueClosed);
+        }
+
+        return Ok(());
+    }
+
+    pub fn try_submit(&self, request: QueueRequest) -> Result<(), XEngineError> {
+        let Some(sender) = self.sender.as_ref() else {
+            return Err(XEngineError::QueueClosed);
+        };
+
+        match sender.try_send(request) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(_)) => return Err(XEngineError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => return Err(XEngineError::QueueClosed),
+        }
+    }
+
+    // Queued requests are completed before the worker stops
+    pub fn shutdown(mut self) -> Arc<SharedVolume> {
+        self.stop();
+
+        return self.shared.clone();
+    }
+
+    fn stop(&mut self) {
+        self.sender.take();
+
+        if let Some(worker) = self.worker.take() {
+            worker.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for VolumeQueue {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_worker(shared: Arc<SharedVolume>, receiver: Receiver<QueueRequest>, max_batch: usize) {
+    // Stored writes waiting for the sync of their group
+    let mut held: Vec<WriteResult> = Vec::new();
+
+    loop {
+        let timeout = group_timeout(&shared);
+
+        if timeout.is_none() && !held.is_empty() {
+            flush_group(&shared, &mut held);
+        }
+
+        let request = match timeout {
+            Some(timeout) => match receiver.recv_timeout(timeout) {
+                Ok(request) => request,
+                Err(RecvTimeoutError::Timeout) => {
+                    flush_group(&shared, &mut held);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match receiver.recv() {
+                Ok(request) => request,
+                Err(_) => break,
+            },
+        };
+
+        let mut writes = Vec::new();
+        let mut reads: Vec<(String, Completion<Option<Chunk>>)> = Vec::new();
+        let mut next = Some(request);
+
+        while let Some(request) = next {
+            match request {
+                QueueRequest::Write { chunk, done } => {
+                    // Writes go first, so a chunk read earlier in the batch is read before it changes
+                    if reads.iter().any(|(uid, _)| *uid == chunk.uid) {
+                        apply_writes(&shared, std::mem::take(&mut writes), &mut held);
+                        apply_reads(&shared, std::mem::take(&mut reads));
+                    }
+                    writes.push((chunk, done));
+                }
+                QueueRequest::Read { uid, done } => reads.push((uid, done)),
+            }
+
+            next = if writes.len() + reads.len() < max_batch {
+                receiver.try_recv().ok()
+            } else {
+                None
+            };
+        }
+
+        apply_writes(&shared, writes, &mut held);
+        apply_reads(&shared, reads);
+    }
+
+    // A stopped queue leaves no group behind
+    flush_group(&shared, &mut held);
+    shared.write().set_group_timer(false);
+}
+
+// Time left before the open group is synced, a closed one is checked again after an interval
+fn group_timeout(shared: &SharedVolume) -> Option<Duration> {
+    let volume = shared.read();
+
+    let interval = match volume.durability {
+        Durability::GroupCommit { interval_ms, .. } if interval_ms > 0 => Duration::from_millis(interval_ms),
+        _ => return None,
+    };
+
+    match volume.unsynced_since() {
+        Some(start) => return Some((start + interval).saturating_duration_since(Instant::now())),
+        None => return Some(interval),
+    }
+}
+
+fn flush_group(shared: &SharedVolume, held: &mut Vec<WriteResult>) {
+    let mut volume = shared.write();
+
+    let result = if matches!(volume.durability, Durability::GroupCommit { .. }) && volume.has_unsynced() {
+        volume.flush(shared.file())
+    } else {
+        Ok(())
+    };
+
+    drop(volume);
+
+    // A failed sync is reported to the writes of the group, the next commit retries it
+    for (done, stored) in held.drain(..) {
+        match &result {
+            Ok(()) => done.complete(stored),
+            Err(err) => done.complete(Err(XEngineError::IO(io::Error::other(format!("{err:?}"))))),
+        }
+    }
+}
+
+type WriteResult = (Completion<Option<String>>, Result<Option<String>, XEngineError>);
+
+fn apply_writes(shared: &SharedVolume, writes: Vec<(Chunk, Completion<Option<String>>)>, held: &mut Vec<WriteResult>) {
+    if writes.is_empty() {
+        return;
+    }
+
+    let mut volume = shared.write();
+    let file = shared.file();
+
+    let chunks: Vec<Chunk> = writes.iter().map(|(chunk, _)| chunk.clone()).collect();
+
+    let results: Vec<WriteResult> = if volume.add_chunks_v2(file, &chunks).is_ok() {
+        let volume_uid = volume.uid.clone();
+
+        writes.into_iter().map(|(_, done)| (done, Ok(Some(volume_uid.clone())))).collect()
+    } else {
+        // A failed batch is rolled back as a whole, the chunks are retried one by one
+        // so each request gets its own result
+        writes
+            .into_iter()
+            .map(|(chunk, done)| (done, volume.add_chunk_v2(file, chunk)))
+            .collect()
+    };
+
+    // A stored write is completed once its group is synced
+    let grouped = matches!(volume.durability, Durability::GroupCommit { .. }) && volume.has_unsynced();
+
+    drop(volume);
+
+    if grouped {
+        for (done, result) in results {
+            match result {
+                Ok(stored) => held.push((done, Ok(stored))),
+                Err(err) => done.complete(Err(err)),
+            }
+        }
+        return;
+    }
+
+    for (done, result) in held.drain(..).chain(results) {
+        done.complete(result);
+    }
+}
+
+fn apply_reads(shared: &SharedVolume, reads: Vec<(String, Completion<Option<Chunk>>)>) {
+    if reads.is_empty() {
+        return;
+    }
+
+    let uids: Vec<String> = reads.iter().map(|(uid, _)| uid.clone()).collect();
+
+    if let Ok(chunks) = shared.get_chunks(&uids) {
+        for ((_, done), chunk) in reads.into_iter().zip(chunks) {
+            done.complete(Ok(chunk));
+        }
+        return;
+    }
+
+    // A corrupt chunk fails the whole batch, read one by one to report it only to its request
+    for (uid, done) in reads {
+        done.complete(shared.get_chunk(uid));
+    }
+}
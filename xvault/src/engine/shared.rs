@@ -0,0 +1,132 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::engine::{
+    chunk::{Chunk, ChunksHandler},
+    error::XEngineError,
+    volume::Volume,
+};
+
+/*
+    Volume shared between threads, owning its file handle.
+
+    Chunks are read through &self with positional reads, so any number of readers
+    run at the same time. Writers take the volume exclusively and are serialized.
+*/
+#[derive(Debug)]
+pub struct SharedVolume {
+    volume: RwLock<Volume>,
+    file: File,
+}
+
+impl SharedVolume {
+    pub fn new(volume: Volume, file: File) -> Self {
+        return Self {
+            volume: RwLock::new(volume),
+            file,
+        };
+    }
+
+    pub fn open_existing(path: String, write: bool) -> Result<Self, XEngineError> {
+        let (volume, file) = Volume::open_existing(path, write)?;
+
+        return Ok(Self::new(volume, file));
+    }
+
+    pub fn open_existing_uncached(path: String, write: bool) -> Result<Self, XEngineError> {
+        let (volume, file) = Volume::open_existing_uncached(path, write)?;
+
+        return Ok(Self::new(volume, file));
+    }
+
+    // A writer that panicked leaves the on disk metadata consistent thanks to the
+    // journal, so a poisoned lock is still usable
+    pub fn read(&self) -> RwLockReadGuard<'_, Volume> {
+        return self.volume.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, Volume> {
+        return self.volume.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+
+    pub fn file(&self) -> &File {
+        return &self.file;
+    }
+
+    pub fn get_chunk(&self, uuid: String) -> Result<Option<Chunk>, XEngineError> {
+        return self.read().read_chunk(&self.file, uuid);
+    }
+
+    pub fn get_chunks(&self, uuids: &[String]) -> Result<Vec<Option<Chunk>>, XEngineError> {
+        return self.read().read_chunks(&self.file, uuids);
+    }
+
+    pub fn add_chunk(&self, chunk: Chunk) -> Result<Option<String>, XEngineError> {
+        return self.write().add_chunk_v2(&self.file, chunk);
+    }
+
+    pub fn add_chunks(&self, chunks: &Vec<Chunk>) -> Result<(), XEngineError> {
+        return self.write().add_chunks_v2(&self.file, chunks);
+    }
+
+    pub fn remove_chunk(&self, uuid: String) -> Result<Option<String>, XEngineError> {
+        return self.write().remove_chunk_v2(&self.file, uuid);
+    }
+
+    // Readers only wait while a map entry is updated, never while chunk data is copied
+    pub fn compact(&self, live_chunks: &HashSet<String>) -> Result<u64, XEngineError> {
+        let mut compaction = self.write().begin_compaction(&self.file, live_chunks)?;
+        let mut result = Ok(());
+
+        while let Some(relocation) = compaction.next_relocation() {
+            result = self.read().copy_relocation(&self.file, &relocation);
+
+            if result.is_ok() {
+                result = self.write().apply_relocation(&self.file, &relocation).map(|_| ());
+            }
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        let reclaimed = self.write().finish_compaction(&self.file, compaction);
+        result?;
+
+        return reclaimed;
+    }
+
+    pub fn get_actual_size(&self) -> u64 {
+        return self.read().get_actual_size();
+    }
+
+    pub fn get_max_size(&self) -> u64 {
+        return self.read().get_max_size();
+    }
+
+    pub fn into_inner(self) -> (Volume, File) {
+        let volume = self.volume.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        return (volume, self.file);
+    }
+}
@@ -17,7 +17,7 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::str::FromStr;
 
-use crate::engine::{error::XEngineError, volume::ChunkOffset};
+use crate::engine::{compression::ChunkCodec, crypto::ChunkCipher, error::XEngineError, volume::{ChunkMeta, ChunkOffset}};
 use bincode::config::{Configuration, LittleEndian};
 use uuid::Uuid;
 
@@ -60,6 +60,10 @@ pub fn encode_number(
     return Ok(number_emcoded);
 }
 
+pub fn compute_checksum(buf: &[u8]) -> Number {
+    return crc32c::crc32c(buf) as Number;
+}
+
 pub fn decode_uuid(buf: [u8; UID_LEN]) -> Uuid {
     return Uuid::from_bytes_le(buf);
 }
@@ -94,6 +98,8 @@ pub fn encode_uuid_from_string(uid: String) -> Result<[u8; 16], XEngineError> {
 pub struct ParseOffsetMapElem {
     pub uid: String,
     pub offset: ChunkOffset,
+    pub flags: Number,
+    pub meta: ChunkMeta,
 }
 
 pub fn parse_offset_map_elem(
@@ -105,16 +111,83 @@ pub fn parse_offset_map_elem(
     let chunk_uid_bytes = &buf[..index];
     let uid = decode_uuid_to_string(chunk_uid_bytes.try_into().unwrap());
 
-    let chunk_start_bytes = &buf[index..(index + 8)];
+    let offset = parse_chunk_offset(&buf[index..(index + 16)], config)?;
+
+    let flags_bytes = &buf[(index + 16)..(index + 24)];
+    let flags = decode_number(flags_bytes, &config)?;
+
+    let checksum_bytes = &buf[(index + 24)..(index + 32)];
+    let checksum = decode_number(checksum_bytes, &config)?;
+
+    let refcount_bytes = &buf[(index + 32)..(index + 40)];
+    let refcount = decode_number(refcount_bytes, &config)?;
+
+    let codec_bytes = &buf[(index + 40)..(index + 48)];
+    let codec = ChunkCodec::from_number(decode_number(codec_bytes, &config)?)?;
+
+    let raw_len_bytes = &buf[(index + 48)..(index + 56)];
+    let raw_len = decode_number(raw_len_bytes, &config)?;
+
+    let cipher_bytes = &buf[(index + 56)..(index + 64)];
+    let cipher = ChunkCipher::from_number(decode_number(cipher_bytes, &config)?)?;
+
+    let raw_checksum_bytes = &buf[(index + 64)..(index + 72)];
+    let raw_checksum = decode_number(raw_checksum_bytes, &config)?;
+
+    return Ok(ParseOffsetMapElem {
+        uid,
+        offset,
+        flags,
+        meta: ChunkMeta { checksum, refcount, codec, raw_len, cipher, raw_checksum },
+    });
+}
+
+pub fn encode_offset_map_elem(
+    uid: String,
+    offset: &ChunkOffset,
+    flags: Number,
+    meta: &ChunkMeta,
+    config: Configuration<LittleEndian, bincode::config::Fixint>,
+) -> Result<Vec<u8>, XEngineError> {
+    let mut buf = Vec::with_capacity(UID_LEN + 72);
+
+    buf.extend_from_slice(&encode_uuid_from_string(uid)?);
+    buf.extend_from_slice(&encode_chunk_offset(offset, config)?);
+    buf.extend_from_slice(&encode_number(flags, config)?);
+    buf.extend_from_slice(&encode_number(meta.checksum, config)?);
+    buf.extend_from_slice(&encode_number(meta.refcount, config)?);
+    buf.extend_from_slice(&encode_number(meta.codec.to_number(), config)?);
+    buf.extend_from_slice(&encode_number(meta.raw_len, config)?);
+    buf.extend_from_slice(&encode_number(meta.cipher.to_number(), config)?);
+    buf.extend_from_slice(&encode_number(meta.raw_checksum, config)?);
+
+    return Ok(buf);
+}
+
+pub fn parse_chunk_offset(
+    buf: &[u8],
+    config: Configuration<LittleEndian, bincode::config::Fixint>,
+) -> Result<ChunkOffset, XEngineError> {
+    let chunk_start_bytes = &buf[..8];
     let chunk_start = decode_number(chunk_start_bytes, &config)?;
 
-    let chunk_end_bytes = &buf[(index + 8)..(index + 16)];
+    let chunk_end_bytes = &buf[8..16];
     let chunk_end = decode_number(chunk_end_bytes, &config)?;
 
-    let offset = ChunkOffset {
+    return Ok(ChunkOffset {
         start: chunk_start,
         end: chunk_end,
-    };
+    });
+}
+
+pub fn encode_chunk_offset(
+    offset: &ChunkOffset,
+    config: Configuration<LittleEndian, bincode::config::Fixint>,
+) -> Result<Vec<u8>, XEngineError> {
+    let mut buf = Vec::with_capacity(16);
+
+    buf.extend_from_slice(&encode_number(offset.start, config)?);
+    buf.extend_from_slice(&encode_number(offset.end, config)?);
 
-    return Ok(ParseOffsetMapElem { uid, offset });
+    return Ok(buf);
 }
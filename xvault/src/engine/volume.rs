@@ -17,7 +17,7 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 pub use bincode::{Decode, Encode};
 pub use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::OpenOptions, io::{Seek, SeekFrom, Write}, os::unix::fs::FileExt, vec};
+use std::{collections::{HashMap, HashSet}, fs::{OpenOptions, TryLockError}, io::{Seek, SeekFrom}, os::unix::fs::FileExt, time::{Duration, Instant}, vec};
 pub use std::{
     fs::{self, File},
     io::{self, Read},
@@ -25,14 +25,45 @@ pub use std::{
 };
 pub use uuid::Uuid;
 
+#[cfg(feature = "mmap")]
+use std::borrow::Cow;
+
+#[cfg(feature = "mmap")]
+use crate::engine::mmap::{VolumeMap, VolumeMapping};
+
+#[cfg(feature = "encryption")]
+use crate::engine::crypto::{derive_master_key, generate_key, generate_salt, unwrap_key, wrap_key};
+
 use crate::engine::{
-    chunk::{Chunk, ChunksHandler, CHUNK_SIZE},
+    chunk::{is_valid_chunk_size, Chunk, ChunksHandler, CHUNK_SIZE},
+    compression::{compress_chunk, ChunkCodec},
+    crypto::{decrypt_chunk, encrypt_chunk, ChunkCipher, ChunkKey, VolumeKeySlots, NONCE_LEN, SALT_LEN, TAG_LEN, WRAPPED_KEY_LEN},
     error::XEngineError,
-    utils::{decode_number, decode_uuid_to_string, encode_number, encode_uuid_from_string, get_bincode_config, parse_offset_map_elem},
+    io_engine::{coalesce_writes, read_batch, write_batch, IoEngine, ReadRequest},
+    xfile::XFile,
+    journal::{append_journal, apply_journal, apply_journal_with, clear_journal, journal_path, read_journal, write_journal_with, Durability, JournalRecord},
+    utils::{decode_number, decode_uuid_to_string, encode_number, encode_offset_map_elem, encode_uuid_from_string, get_bincode_config, parse_offset_map_elem, compute_checksum, encode_chunk_offset, parse_chunk_offset, ParseOffsetMapElem},
 };
 
+pub const VOLUME_MAGIC: [u8; 8] = *b"XVAULTFS";
+pub const VOLUME_FORMAT_VERSION: u64 = 2;
+pub const VOLUME_FEATURES: u64 = VOLUME_FEATURE_ENCRYPTED; // Feature flags known by this version
+
+pub const VOLUME_FEATURE_ENCRYPTED: u64 = 1; // Key slots hold a wrapped data key
+
+const SUPERBLOCK_LEN: u64 = 512; // Reserved bytes, the checksum is always the last field
+
+const MAGIC_LEN: u64 = 8;
+const OFFSET_MAGIC: u64 = 0;
+
+const FORMAT_VERSION_LEN: u64 = 8; //u64 size
+const OFFSET_FORMAT_VERSION: u64 = OFFSET_MAGIC + MAGIC_LEN;
+
+const FEATURES_LEN: u64 = 8; //u64 size
+const OFFSET_FEATURES: u64 = OFFSET_FORMAT_VERSION + FORMAT_VERSION_LEN;
+
 const UID_LEN: u64 = 16; // UUID size in bytes
-const OFFSET_VOLUME_UID: u64 = 0;
+const OFFSET_VOLUME_UID: u64 = OFFSET_FEATURES + FEATURES_LEN;
 
 const MAX_SIZE_LEN: u64 = 8; //u64 size
 const OFFSET_MAX_SIZE: u64 = OFFSET_VOLUME_UID + UID_LEN;
@@ -40,18 +71,97 @@ const OFFSET_MAX_SIZE: u64 = OFFSET_VOLUME_UID + UID_LEN;
 const ACTUAL_SIZE_LEN: u64 = 8; //u64 size
 const OFFSET_ACTUAL_SIZE: u64 = OFFSET_MAX_SIZE + MAX_SIZE_LEN;
 
+const MAP_OFFSETS_START_LEN: u64 = 8; //u64 size
+const OFFSET_MAP_OFFSETS_START: u64 = OFFSET_ACTUAL_SIZE + ACTUAL_SIZE_LEN;
+
+const MAP_OFFSETS_COUNT_LEN: u64 = 8; //u64 size
+const OFFSET_MAP_OFFSETS_COUNT: u64 = OFFSET_MAP_OFFSETS_START + MAP_OFFSETS_START_LEN;
+
+const FREE_EXTENTS_START_LEN: u64 = 8; //u64 size
+const OFFSET_FREE_EXTENTS_START: u64 = OFFSET_MAP_OFFSETS_COUNT + MAP_OFFSETS_COUNT_LEN;
+
+const FREE_EXTENTS_COUNT_LEN: u64 = 8; //u64 size
+const OFFSET_FREE_EXTENTS_COUNT: u64 = OFFSET_FREE_EXTENTS_START + FREE_EXTENTS_START_LEN;
+
+const DATA_START_LEN: u64 = 8; //u64 size
+const OFFSET_DATA_START: u64 = OFFSET_FREE_EXTENTS_COUNT + FREE_EXTENTS_COUNT_LEN;
+
+const DATA_END_LEN: u64 = 8; //u64 size
+const OFFSET_DATA_END: u64 = OFFSET_DATA_START + DATA_START_LEN;
+
+const DATA_HEAD_LEN: u64 = 8; //u64 size
+const OFFSET_DATA_HEAD: u64 = OFFSET_DATA_END + DATA_END_LEN;
+
+const CHUNK_SIZE_LEN: u64 = 8; //u64 size
+const OFFSET_CHUNK_SIZE: u64 = OFFSET_DATA_HEAD + DATA_HEAD_LEN;
+
+const KEY_SALT_LEN: u64 = SALT_LEN as u64;
+const OFFSET_KEY_SALT: u64 = OFFSET_CHUNK_SIZE + CHUNK_SIZE_LEN;
+
+const KEY_CURRENT_LEN: u64 = WRAPPED_KEY_LEN as u64;
+const OFFSET_KEY_CURRENT: u64 = OFFSET_KEY_SALT + KEY_SALT_LEN;
+
+const KEY_PREVIOUS_LEN: u64 = WRAPPED_KEY_LEN as u64;
+const OFFSET_KEY_PREVIOUS: u64 = OFFSET_KEY_CURRENT + KEY_CURRENT_LEN;
+
+const MAP_OFFSETS_TOMBSTONES_LEN: u64 = 8; //u64 size
+const OFFSET_MAP_OFFSETS_TOMBSTONES: u64 = OFFSET_KEY_PREVIOUS + KEY_PREVIOUS_LEN;
+
+const HEADER_LEN: u64 = OFFSET_MAP_OFFSETS_TOMBSTONES + MAP_OFFSETS_TOMBSTONES_LEN;
+
+const HEADER_CHECKSUM_LEN: u64 = 8; //u64 size
+const OFFSET_HEADER_CHECKSUM: u64 = SUPERBLOCK_LEN - HEADER_CHECKSUM_LEN;
+
+const _: () = assert!(HEADER_LEN <= OFFSET_HEADER_CHECKSUM, "Volume header fields overflow the superblock");
+
 const MAP_OFFSETS_ELEM_CHUNK_UID_LEN: u64 = 16; // UUID size in bytes
 const MAP_OFFSETS_ELEM_OFFSET_START_LEN: u64 = 8; // u64 size
 const MAP_OFFSETS_ELEM_OFFSET_END_LEN: u64 = 8; // u64 size
-
-const MAP_OFFSETS_ELEM_LEN: u64 = MAP_OFFSETS_ELEM_CHUNK_UID_LEN
+const MAP_OFFSETS_ELEM_FLAGS_LEN: u64 = 8; // u64 size
+const MAP_OFFSETS_ELEM_CHECKSUM_LEN: u64 = 8; // u64 size
+const MAP_OFFSETS_ELEM_REFCOUNT_LEN: u64 = 8; // u64 size
+const MAP_OFFSETS_ELEM_CODEC_LEN: u64 = 8; // u64 size
+const MAP_OFFSETS_ELEM_RAW_LEN_LEN: u64 = 8; // u64 size
+const MAP_OFFSETS_ELEM_CIPHER_LEN: u64 = 8; // u64 size
+const MAP_OFFSETS_ELEM_RAW_CHECKSUM_LEN: u64 = 8; // u64 size
+
+pub const MAP_OFFSETS_ELEM_LEN: u64 = MAP_OFFSETS_ELEM_CHUNK_UID_LEN
     + MAP_OFFSETS_ELEM_OFFSET_START_LEN
-    + MAP_OFFSETS_ELEM_OFFSET_END_LEN;
+    + MAP_OFFSETS_ELEM_OFFSET_END_LEN
+    + MAP_OFFSETS_ELEM_FLAGS_LEN
+    + MAP_OFFSETS_ELEM_CHECKSUM_LEN
+    + MAP_OFFSETS_ELEM_REFCOUNT_LEN
+    + MAP_OFFSETS_ELEM_CODEC_LEN
+    + MAP_OFFSETS_ELEM_RAW_LEN_LEN
+    + MAP_OFFSETS_ELEM_CIPHER_LEN
+    + MAP_OFFSETS_ELEM_RAW_CHECKSUM_LEN;
+
+// The offset map is an open addressing hash table keyed by chunk uid,
+// kept half empty so a lookup takes a few probes
+const MAP_OFFSETS_SLOTS_PER_CHUNK: u64 = 2;
+
+// Tombstones lengthen every probe sequence, past this fraction of the slots the map is rehashed
+const MAP_OFFSETS_MAX_TOMBSTONES_DIVISOR: u64 = 4;
+
+pub const MAP_OFFSETS_ELEM_EMPTY: u64 = 0;
+pub const MAP_OFFSETS_ELEM_LIVE: u64 = 1;
+pub const MAP_OFFSETS_ELEM_TOMBSTONE: u64 = 2;
+
+const FREE_EXTENTS_ELEM_LEN: u64 = 16; // start + end
+
+const MAP_OFFSETS_START_OFFSET: u64 = SUPERBLOCK_LEN;
+
+// An encrypted chunk keeps its decoded length inside the ciphertext, before the encoded data
+const SEALED_RAW_LEN_LEN: usize = 8; // u64 size
+
+// Room for the nonce, tag and sealed length of an encrypted chunk, so a full volume of full chunks always fits
+const CHUNK_OVERHEAD_LEN: u64 = (NONCE_LEN + TAG_LEN + SEALED_RAW_LEN_LEN) as u64;
 
-const MAP_OFFSETS_START_OFFSET: u64 = UID_LEN + MAX_SIZE_LEN + ACTUAL_SIZE_LEN;
+// The unsynced metadata is kept in memory and rewritten to the journal on flush, past this length it is flushed
+const PENDING_JOURNAL_MAX_LEN: usize = 1 << 20;
 
 //pub type VolumeChunkOffset = [u8; 2];
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub struct ChunkOffset {
     pub start: u64,
     pub end: u64,
@@ -63,7 +173,133 @@ impl Default for ChunkOffset {
     }
 }
 
+impl ChunkOffset {
+    pub fn len(&self) -> u64 {
+        return self.end - self.start;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.start == self.end;
+    }
+}
+
+// Per chunk data stored in the offset map entry next to the chunk offset
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChunkMeta {
+    pub checksum: u64,
+    // Number of adds of the same chunk, its extent is freed when it drops to zero
+    pub refcount: u64,
+    // The extent holds data encoded with codec then encrypted with cipher,
+    // raw_len is the decoded length
+    pub codec: ChunkCodec,
+    pub raw_len: u64,
+    pub cipher: ChunkCipher,
+    // Checksum of the decoded data, the same chunk matches whatever its codec.
+    // Both are 0 for an encrypted chunk, they would reveal its plaintext
+    pub raw_checksum: u64,
+}
+
+impl ChunkMeta {
+    pub fn from_data(data: &[u8]) -> Self {
+        let checksum = compute_checksum(data);
+
+        return Self {
+            checksum,
+            refcount: 1,
+            codec: ChunkCodec::None,
+            raw_len: data.len() as u64,
+            cipher: ChunkCipher::None,
+            raw_checksum: checksum,
+        };
+    }
+
+    // The checksum covers the stored bytes, so chunks are verified before being decoded
+    pub fn from_stored(stored: &[u8], codec: ChunkCodec, cipher: ChunkCipher, data: &[u8]) -> Self {
+        let (raw_len, raw_checksum) = match cipher {
+            ChunkCipher::None => (data.len() as u64, compute_checksum(data)),
+            _ => (0, 0),
+        };
+
+        return Self {
+            checksum: compute_checksum(stored),
+            refcount: 1,
+            codec,
+            raw_len,
+            cipher,
+            raw_checksum,
+        };
+    }
+}
+
+/*
+    Volume file regions:
+    header | offset map (2 * max_size slots) | free extents (max_size entries) | chunks data (max_size chunks)
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct VolumeLayout {
+    pub map_offsets_start: u64,
+    pub free_extents_start: u64,
+    pub data_start: u64,
+    pub data_end: u64,
+}
+
+impl VolumeLayout {
+    pub fn from_max_size(max_size: u64, chunk_size: u64) -> Self {
+        let map_offsets_start = MAP_OFFSETS_START_OFFSET;
+        let free_extents_start = map_offsets_start + (MAP_OFFSETS_ELEM_LEN * Self::map_offsets_slots(max_size));
+        let free_extents_end = free_extents_start + (FREE_EXTENTS_ELEM_LEN * max_size);
+
+        // Chunk aligned, so the space given back by a bigger offset map is made of whole chunks
+        let data_start = free_extents_end.div_ceil(chunk_size) * chunk_size;
+        let data_end = data_start + ((chunk_size + CHUNK_OVERHEAD_LEN) * max_size);
+
+        return Self {
+            map_offsets_start,
+            free_extents_start,
+            data_start,
+            data_end,
+        };
+    }
+
+    pub fn map_offsets_slots(max_size: u64) -> u64 {
+        return max_size * MAP_OFFSETS_SLOTS_PER_CHUNK;
+    }
+
+    pub fn map_offsets_end(&self, slots: u64) -> u64 {
+        return self.map_offsets_start + (MAP_OFFSETS_ELEM_LEN * slots);
+    }
+
+    pub fn free_extents_end(&self, max_size: u64) -> u64 {
+        return self.free_extents_start + (FREE_EXTENTS_ELEM_LEN * max_size);
+    }
+
+    pub fn contains(&self, offset: &ChunkOffset) -> bool {
+        return offset.start >= self.data_start
+            && offset.start <= offset.end
+            && offset.end <= self.data_end;
+    }
+}
+
 pub type VolumeOffsets = HashMap<String, ChunkOffset>;
+pub type VolumeChunksMeta = HashMap<String, ChunkMeta>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ScrubReport {
+    pub ok: Vec<String>,
+    pub corrupt: Vec<String>,
+    pub out_of_bounds: Vec<String>,
+    pub overlapping: Vec<(String, String)>,
+    pub overlapping_free: Vec<String>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        return self.corrupt.is_empty()
+            && self.out_of_bounds.is_empty()
+            && self.overlapping.is_empty()
+            && self.overlapping_free.is_empty();
+    }
+}
 pub type VolumeChunks = HashMap<String, Chunk>;
 
 #[derive(Decode, Encode)]
@@ -72,13 +308,145 @@ struct VolumeWrap {
     pub data: Volume,
 }
 
+// Chunks of a compaction still to be visited, ordered by their offset
+#[derive(Debug)]
+pub struct VolumeCompaction {
+    chunks: Vec<Relocation>,
+    cursor: u64,
+    old_head: u64,
+}
+
+impl VolumeCompaction {
+    // The next chunk to move toward the data start, chunks that cannot move without
+    // overlapping their own data stay in place
+    pub fn next_relocation(&mut self) -> Option<Relocation> {
+        while let Some(mut chunk) = self.chunks.pop() {
+            let target = ChunkOffset {
+                start: self.cursor,
+                end: self.cursor + chunk.offset.len(),
+            };
+
+            if target.end <= chunk.offset.start {
+                self.cursor = target.end;
+                chunk.target = target;
+
+                return Some(chunk);
+            }
+
+            self.cursor = self.cursor.max(chunk.offset.end);
+        }
+
+        return None;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    slot: u64,
+    uid: String,
+    offset: ChunkOffset,
+    meta: ChunkMeta,
+    target: ChunkOffset,
+}
+
+impl Relocation {
+    pub fn uid(&self) -> &str {
+        return &self.uid;
+    }
+}
+
+/*
+    Chunk data written by a batch is submitted at once before the metadata journal,
+    extents released by the batch are freed only after that so the batch never
+    overwrites data still referenced on disk
+*/
+#[derive(Clone, Debug, Default)]
+struct DataBatch {
+    writes: Vec<JournalRecord>,
+    released: Vec<ChunkOffset>,
+}
+
+/*
+    In-memory metadata as it was when a transaction began, restored when the
+    transaction fails so the volume matches the offset map left on disk.
+    Cached entries are saved the first time the transaction changes them
+*/
+#[derive(Clone, Debug, Default)]
+struct VolumeRollback {
+    actual_size: u64,
+    data_head: u64,
+    max_size: u64,
+    layout: VolumeLayout,
+    map_offsets_count: u64,
+    map_offsets_tombstones: u64,
+    free_extents: Vec<ChunkOffset>,
+    held_extents: usize,
+    unsynced_chunks: u64,
+    cached: HashMap<String, CachedEntry>,
+}
+
+type CachedEntry = (Option<ChunkOffset>, Option<ChunkMeta>, Option<Chunk>);
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Volume {
     pub uid: String,
     pub max_size: u64,
+    pub chunk_size: u64,
     pub path: String,
+    pub layout: VolumeLayout,
     pub chunks: VolumeChunks,
     pub offsets: VolumeOffsets,
+    pub chunks_meta: VolumeChunksMeta,
+    pub map_offsets_count: u64,
+    pub map_offsets_tombstones: u64,
+    pub free_extents: Vec<ChunkOffset>,
+    pub actual_size: u64,
+    pub data_head: u64,
+    pub cached: bool,
+    // Codec for new chunks, stored chunks keep the codec of their map entry
+    pub codec: ChunkCodec,
+    // Engine for the batched chunk reads and writes
+    pub io_engine: IoEngine,
+    // When committed writes are synced to disk
+    pub durability: Durability,
+    // New chunks are encrypted when a key is set
+    #[serde(skip)]
+    key: Option<ChunkKey>,
+    #[serde(skip)]
+    previous_key: Option<ChunkKey>,
+    #[serde(skip)]
+    key_slots: VolumeKeySlots,
+    #[serde(skip)]
+    journal: Option<Vec<JournalRecord>>,
+    #[serde(skip)]
+    rollback: Option<VolumeRollback>,
+    // Metadata committed but not yet synced, applied to the volume file by flush
+    #[serde(skip)]
+    pending: Vec<JournalRecord>,
+    // Extents freed by pending metadata, the volume file still references them until the flush
+    #[serde(skip)]
+    held_extents: Vec<ChunkOffset>,
+    // A queue worker closes the open group when its interval expires
+    #[serde(skip)]
+    group_timer: bool,
+    #[serde(skip)]
+    batch: Option<DataBatch>,
+    // Chunk data written since the last commit
+    #[serde(skip)]
+    data_dirty: bool,
+    // Chunks stored or removed since the last sync and when the first commit after it was
+    #[serde(skip)]
+    unsynced_chunks: u64,
+    #[serde(skip)]
+    group_start: Option<Instant>,
+    #[serde(skip)]
+    compacting: bool,
+    // Opened for writing, under the exclusive lock
+    #[serde(skip)]
+    writable: bool,
+    #[cfg(feature = "mmap")]
+    #[serde(skip)]
+    mmap: VolumeMapping,
 }
 
 impl Default for Volume {
@@ -87,8 +455,36 @@ impl Default for Volume {
             uid: Default::default(),
             path: Default::default(),
             max_size: Default::default(),
+            chunk_size: CHUNK_SIZE as u64,
+            layout: Default::default(),
             chunks: Default::default(),
             offsets: Default::default(),
+            chunks_meta: Default::default(),
+            map_offsets_count: Default::default(),
+            map_offsets_tombstones: Default::default(),
+            free_extents: Default::default(),
+            actual_size: Default::default(),
+            data_head: Default::default(),
+            cached: true,
+            codec: Default::default(),
+            io_engine: Default::default(),
+            durability: Default::default(),
+            key: Default::default(),
+            previous_key: Default::default(),
+            key_slots: Default::default(),
+            journal: Default::default(),
+            rollback: Default::default(),
+            pending: Default::default(),
+            held_extents: Default::default(),
+            group_timer: Default::default(),
+            batch: Default::default(),
+            data_dirty: Default::default(),
+            unsynced_chunks: Default::default(),
+            group_start: Default::default(),
+            compacting: Default::default(),
+            writable: Default::default(),
+            #[cfg(feature = "mmap")]
+            mmap: Default::default(),
         }
     }
 }
@@ -100,6 +496,9 @@ impl Volume {
 
     pub fn build(&mut self) -> Result<&mut Self, XEngineError> {
         assert!(self.max_size > 0, "Volume max_size cannot be 0");
+        assert!(is_valid_chunk_size(self.chunk_size as usize), "Volume chunk_size must be a power of two between 4 KiB and 4 MiB");
+        assert!(self.codec.is_available(), "Volume codec {:?} is not enabled", self.codec);
+        assert!(self.key.is_none() || ChunkCipher::ChaCha20Poly1305.is_available(), "Volume encryption is not enabled");
         assert!(!self.path.is_empty(), "Volume path cannot be empty");
         assert!(!self.uid.is_empty(), "Volume uid cannot be empty");
 
@@ -146,12 +545,13 @@ impl Volume {
 
     pub fn set_path(&mut self, path: String) -> &mut Self {
         let path = Path::new(&path);
-        if !path.is_absolute() {
-            let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
-            let path = path.to_str().unwrap_or("").to_string();
+        let path = if !path.is_absolute() {
+            fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+        } else {
+            path.to_path_buf()
+        };
 
-            self.path = path;
-        }
+        self.path = path.to_str().unwrap_or("").to_string();
         return self;
     }
 
@@ -187,6 +587,83 @@ impl Volume {
         return self;
     }
 
+    pub fn set_chunk_size(&mut self, chunk_size: u64) -> &mut Self {
+        self.chunk_size = chunk_size;
+        return self;
+    }
+
+    pub fn set_codec(&mut self, codec: ChunkCodec) -> &mut Self {
+        self.codec = codec;
+        return self;
+    }
+
+    pub fn set_io_engine(&mut self, io_engine: IoEngine) -> &mut Self {
+        self.io_engine = io_engine;
+        return self;
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        return self;
+    }
+
+    pub(crate) fn set_group_timer(&mut self, enabled: bool) {
+        self.group_timer = enabled;
+    }
+
+    pub fn set_key(&mut self, key: ChunkKey) -> &mut Self {
+        self.key = Some(key);
+        return self;
+    }
+
+    pub fn clear_key(&mut self) {
+        self.key = None;
+        self.previous_key = None;
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        return self.key_slots.is_encrypted();
+    }
+
+    pub fn has_key(&self) -> bool {
+        return self.key.is_some();
+    }
+
+    // The offset map of an encrypted volume is readable without the key, so content
+    // addressed uids are keyed with the data key. A data key rotation changes them,
+    // chunks stored before only deduplicate with the files addressed before it
+    fn content_key(&self) -> Result<Option<&ChunkKey>, XEngineError> {
+        if self.key.is_none() && self.key_slots.is_encrypted() {
+            return Err(XEngineError::EncryptionKeyRequired);
+        }
+
+        return Ok(self.key.as_ref());
+    }
+
+    pub fn content_uid(&self, data: &[u8]) -> Result<String, XEngineError> {
+        match self.content_key()? {
+            Some(key) => return Ok(Chunk::build_keyed_content_uid(key, data)),
+            None => return Ok(Chunk::build_content_uid(data)),
+        }
+    }
+
+    // Addresses the chunks of a content addressed file for this volume, before they are added
+    pub fn address_file(&self, xfile: &mut XFile) -> Result<(), XEngineError> {
+        match self.content_key()? {
+            Some(key) => xfile.set_content_uids(|data| Chunk::build_keyed_content_uid(key, data)),
+            None => xfile.set_content_uids(Chunk::build_content_uid),
+        }
+
+        return Ok(());
+    }
+
+    pub fn verify_file_chunks(&self, xfile: &XFile) -> Result<bool, XEngineError> {
+        match self.content_key()? {
+            Some(key) => return Ok(xfile.verify_chunks_with(|data| Chunk::build_keyed_content_uid(key, data))),
+            None => return Ok(xfile.verify_chunks()),
+        }
+    }
+
     pub fn set_max_size_from_disk(&mut self, file: &File) -> Result<(), XEngineError> {
         let max_size = self.read_max_size_from_file(file)?;
         self.set_max_size(max_size);
@@ -223,38 +700,48 @@ impl Volume {
                     return Err(XEngineError::IO(err));
                 }
 
-                let mut file = res.unwrap();
-                let config = get_bincode_config();
+                let file = res.unwrap();
+
+                self.layout = VolumeLayout::from_max_size(self.max_size, self.chunk_size);
+                self.map_offsets_count = VolumeLayout::map_offsets_slots(self.max_size);
+                self.data_head = self.layout.data_start;
 
-                let volume_uid = Uuid::parse_str(&self.uid).unwrap();
-                let volume_uid = volume_uid.to_bytes_le();
+                if let Err(err) = file.set_len(self.layout.data_end) {
+                    return Err(XEngineError::IO(err));
+                }
 
-                let volume_size = 
-                    MAP_OFFSETS_START_OFFSET
-                    + (MAP_OFFSETS_ELEM_LEN * self.max_size)
-                    + (CHUNK_SIZE as u64 * self.max_size); 
-                
-                file.set_len(volume_size).unwrap();
+                return self.write_header(&file);
+            } else {
+                return Err(XEngineError::VolumeAlreadyAllocated);
+            }
+        }
+    }
 
-                let max_size =
-                    bincode::encode_to_vec(&self.max_size, config).map_err(XEngineError::Encode)?;
+    pub fn open_existing(path: String, write: bool) -> Result<(Self, File), XEngineError> {
+        return Self::mount(path, write, true);
+    }
 
-                let actual_size = self.get_actual_size();
-                let actual_size =
-                    bincode::encode_to_vec(&actual_size, config).map_err(XEngineError::Encode)?;
+    // Only the superblock and the free extents are read, chunks are looked up in the on disk offset map
+    pub fn open_existing_uncached(path: String, write: bool) -> Result<(Self, File), XEngineError> {
+        return Self::mount(path, write, false);
+    }
 
-                println!("max_size length: {}", max_size.len());
-                println!("actual_size length: {}", actual_size.len());
+    fn mount(path: String, write: bool, cached: bool) -> Result<(Self, File), XEngineError> {
+        let mut volume = Self::new();
+        volume.set_path(path);
 
-                file.write_all(&volume_uid).unwrap();
-                file.write_all(&max_size).unwrap();
-                file.write_all(&actual_size).unwrap();
+        let file = volume.open(write);
 
-                return Ok(());
-            } else {
-                return Err(XEngineError::VolumeAlreadyAllocated);
-            }
+        if let Err(XEngineError::IO(err)) = &file
+            && err.kind() == io::ErrorKind::NotFound
+        {
+            return Err(XEngineError::FileNotExists);
         }
+
+        let mut file = file?;
+        volume.read_headers(&mut file, cached)?;
+
+        return Ok((volume, file));
     }
 
     pub fn open(&mut self, write: bool) -> Result<File, XEngineError> {
@@ -264,192 +751,2039 @@ impl Volume {
             return Err(XEngineError::IO(err));
         }
 
-        return Ok(file.unwrap());
+        let file = file.unwrap();
+
+        // Advisory lock, exclusive for a writer and shared for readers, released when the file is closed
+        let lock = if write { file.try_lock() } else { file.try_lock_shared() };
+
+        match lock {
+            Ok(()) => {
+                self.writable = write;
+                return Ok(file);
+            }
+            Err(TryLockError::WouldBlock) => return Err(XEngineError::VolumeLocked),
+            Err(TryLockError::Error(err)) => return Err(XEngineError::IO(err)),
+        }
     }
 
     pub fn write_headers(&mut self, file: &mut File) -> Result<(), XEngineError> {
-        //TODO FIX BUG
-        
-        let config = get_bincode_config();
-        let actual_size = self.offsets.len() as u64;
+        let entries = if self.cached {
+            self.offsets
+                .iter()
+                .map(|(uid, offset)| ParseOffsetMapElem {
+                    uid: uid.clone(),
+                    offset: *offset,
+                    flags: MAP_OFFSETS_ELEM_LIVE,
+                    meta: self.chunks_meta.get(uid).copied().unwrap_or_default(),
+                })
+                .collect()
+        } else {
+            self.read_offset_map_entries(file)?
+                .into_iter()
+                .map(|(_, entry)| entry)
+                .collect::<Vec<ParseOffsetMapElem>>()
+        };
+
+        if entries.len() as u64 > self.max_size {
+            return Err(XEngineError::InvalidVolumeLayout);
+        }
+
+        // The map is rewritten without tombstones
+        let map_buf = encode_offset_map(&entries, self.map_offsets_count)?;
 
-        let header_len = MAP_OFFSETS_START_OFFSET + (actual_size * MAP_OFFSETS_ELEM_LEN);
-        let mut buf = vec![0u8; header_len as usize];
-        let buf = buf.as_mut_slice();
+        self.transaction(file, |volume| {
+            volume.write_meta(file, &map_buf, volume.layout.map_offsets_start)?;
+            volume.actual_size = entries.len() as u64;
+            volume.map_offsets_tombstones = 0;
 
+            volume.write_free_extents(file, 0, volume.free_extents.len())?;
 
-        println!("actuoal_size: {actual_size}");
-        println!("header_len {header_len}");
-        println!("MaxOffsets_start_offset {MAP_OFFSETS_START_OFFSET}");
+            return volume.write_header(file);
+        })?;
 
-        let index = UID_LEN as usize;
-        let volume_uid_slice = &mut buf[..index];
-        let volume_uid_bytes = encode_uuid_from_string(self.uid.clone()).unwrap();
-        volume_uid_slice.copy_from_slice(&volume_uid_bytes);
+        // Writing the headers is a checkpoint for the levels not syncing every chunk
+        if matches!(self.durability, Durability::OnHeaderFlush | Durability::GroupCommit { .. }) {
+            return self.flush(file);
+        }
 
-        let max_size_slice = &mut buf[index..(index + 8)];
-        let max_size_bytes = encode_number(self.max_size, config).unwrap();
-        max_size_slice.copy_from_slice(&max_size_bytes);
-        
-        let actual_size_slice = &mut buf[(index + 8)..(index + 16)];
-        let actual_size_bytes = encode_number(actual_size, config).unwrap();
-        actual_size_slice.copy_from_slice(&actual_size_bytes);
+        return Ok(());
+    }
 
-        let map_start = MAP_OFFSETS_START_OFFSET as usize;
+    fn encode_header(&self) -> Result<Vec<u8>, XEngineError> {
+        let config = get_bincode_config();
+        let mut buf = Vec::with_capacity(SUPERBLOCK_LEN as usize);
+
+        buf.extend_from_slice(&VOLUME_MAGIC);
+        buf.extend_from_slice(&encode_number(VOLUME_FORMAT_VERSION, config)?);
+        buf.extend_from_slice(&encode_number(self.get_features(), config)?);
+        buf.extend_from_slice(&encode_uuid_from_string(self.uid.clone())?);
+        buf.extend_from_slice(&encode_number(self.max_size, config)?);
+        buf.extend_from_slice(&encode_number(self.get_actual_size(), config)?);
+        buf.extend_from_slice(&encode_number(self.layout.map_offsets_start, config)?);
+        buf.extend_from_slice(&encode_number(self.map_offsets_count, config)?);
+        buf.extend_from_slice(&encode_number(self.layout.free_extents_start, config)?);
+        buf.extend_from_slice(&encode_number(self.free_extents.len() as u64, config)?);
+        buf.extend_from_slice(&encode_number(self.layout.data_start, config)?);
+        buf.extend_from_slice(&encode_number(self.layout.data_end, config)?);
+        buf.extend_from_slice(&encode_number(self.data_head, config)?);
+        buf.extend_from_slice(&encode_number(self.chunk_size, config)?);
+        buf.extend_from_slice(&self.key_slots.salt);
+        buf.extend_from_slice(&self.key_slots.current);
+        buf.extend_from_slice(&self.key_slots.previous);
+        buf.extend_from_slice(&encode_number(self.map_offsets_tombstones, config)?);
+
+        buf.resize(OFFSET_HEADER_CHECKSUM as usize, 0);
+
+        let checksum = compute_checksum(&buf);
+        buf.extend_from_slice(&encode_number(checksum, config)?);
+
+        return Ok(buf);
+    }
 
-        let offsets_slice = &mut buf[map_start..header_len as usize];
+    fn get_features(&self) -> u64 {
+        if self.key_slots.is_encrypted() {
+            return VOLUME_FEATURE_ENCRYPTED;
+        }
+        return 0;
+    }
 
-        for (i, (uid, offset)) in self.offsets.clone().into_iter().enumerate() {
-            let start = i * 32;
-            let end = start + 32;
+    fn write_header(&mut self, file: &File) -> Result<(), XEngineError> {
+        let header = self.encode_header()?;
 
-            let offset_slice = &mut offsets_slice[start..end];
+        return self.write_meta(file, &header, 0);
+    }
 
-            let chunk_uid_slice = &mut offset_slice[..16];
-            let chunk_uid_bytes = encode_uuid_from_string(uid).unwrap();
-            chunk_uid_slice.copy_from_slice(&chunk_uid_bytes);
-            
-            let chunk_start_slice = &mut offset_slice[16..24];
-            let chunk_start_bytes = encode_number(offset.start, config).unwrap();
-            chunk_start_slice.copy_from_slice(&chunk_start_bytes);
+    // Metadata writes are buffered while a transaction is open
+    fn write_meta(&mut self, file: &File, buf: &[u8], offset: u64) -> Result<(), XEngineError> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(JournalRecord {
+                offset,
+                data: buf.to_vec(),
+            });
 
-            let chunk_end_slice = &mut offset_slice[24..32];
-            let chunk_end_bytes = encode_number(offset.end, config).unwrap();
-            chunk_end_slice.copy_from_slice(&chunk_end_bytes);
+            return Ok(());
         }
 
-        file.write_all_at(buf, 0).unwrap();
+        if let Err(err) = file.write_all_at(buf, offset) {
+            return Err(XEngineError::IO(err));
+        }
 
         return Ok(());
     }
 
-    pub fn read_headers(&mut self, file: &mut File, cached: bool) -> Result<(), XEngineError> {
-        let config = get_bincode_config();
-        let actual_size = self.read_actual_size_from_file(file)?;
-
-        // UID + MAX_SIZE + ACTUAL_SIZE + OFFSET_MAP_OFFSETS + (max_size * 32)
-        let header_len = MAP_OFFSETS_START_OFFSET + (actual_size * MAP_OFFSETS_ELEM_LEN);
-        let mut buf = vec![0u8; header_len as usize];
+    // Chunk data writes are deferred while a batch is open
+    fn write_data(&mut self, file: &File, buf: Vec<u8>, offset: u64) -> Result<(), XEngineError> {
+        if let Some(batch) = self.batch.as_mut() {
+            batch.writes.push(JournalRecord { offset, data: buf });
 
-        let bytes = file.read_exact(&mut buf);
+            return Ok(());
+        }
 
-        if let Err(err) = bytes {
+        if let Err(err) = file.write_all_at(&buf, offset) {
             return Err(XEngineError::IO(err));
         }
+        self.data_dirty = true;
 
-        let buf = buf.as_slice();
-        let volume_uid_bytes = buf
-            [OFFSET_VOLUME_UID as usize..(OFFSET_VOLUME_UID + UID_LEN) as usize]
-            .try_into()
-            .unwrap();
-        let volume_uid = decode_uuid_to_string(volume_uid_bytes);
-        println!("reading uuid: {volume_uid}");
-        self.set_uid(volume_uid);
+        return Ok(());
+    }
 
-        let max_size_bytes =
-            &buf[OFFSET_MAX_SIZE as usize..(OFFSET_MAX_SIZE + MAX_SIZE_LEN) as usize];
-        let max_size = decode_number(max_size_bytes, &config).unwrap();
-        self.set_max_size(max_size);
-        println!("reading max_size: {max_size}");
+    // Reads see the metadata not yet synced and the one buffered by the running transaction
+    fn overlay_journal(&self, buf: &mut [u8], offset: u64) {
+        let end = offset + buf.len() as u64;
 
-        let actual_size_bytes =
-            &buf[OFFSET_ACTUAL_SIZE as usize..(OFFSET_ACTUAL_SIZE + ACTUAL_SIZE_LEN) as usize];
-        let actual_size: u64 = decode_number(actual_size_bytes, &config).unwrap();
-        println!("reading actual_size: {actual_size}");
+        for record in self.pending.iter().chain(self.journal.iter().flatten()) {
+            let record_end = record.offset + record.data.len() as u64;
+
+            if record.offset >= end || record_end <= offset {
+                continue;
+            }
+
+            let from = record.offset.max(offset);
+            let to = record_end.min(end);
 
-        let mut offsets = VolumeOffsets::with_capacity(actual_size as usize);
-        //let mut offsets = VolumeOffsets::new();
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&record.data[(from - record.offset) as usize..(to - record.offset) as usize]);
+        }
+    }
 
-        let mut index = MAP_OFFSETS_START_OFFSET as usize;
+    // The extents held until the next flush can't be allocated yet, a write that
+    // finds the volume full flushes them and is retried once
+    fn retry_full<T, F>(&mut self, file: &File, mut op: F) -> Result<T, XEngineError>
+    where
+        F: FnMut(&mut Self) -> Result<T, XEngineError>,
+    {
+        match op(self) {
+            Err(XEngineError::VolumeFull | XEngineError::VolumeFragmented) if !self.held_extents.is_empty() => {
+                self.flush(file)?;
+                return op(self);
+            }
+            result => return result,
+        }
+    }
 
+    fn transaction<T, F>(&mut self, file: &File, op: F) -> Result<T, XEngineError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, XEngineError>,
+    {
+        // Nested transactions are committed by the outermost one
+        if self.journal.is_some() {
+            return op(self);
+        }
 
-        for _ in 0..actual_size {
-            let map_elem_bytes = &buf[index..(index + MAP_OFFSETS_ELEM_LEN as usize)];
-            let result = parse_offset_map_elem(&map_elem_bytes, config)?;
-            
-            offsets.insert(result.uid, result.offset);
-            index += MAP_OFFSETS_ELEM_LEN as usize;
+        // Without a queue worker nothing would sync a group on time
+        if matches!(self.durability, Durability::GroupCommit { .. }) && !self.group_timer {
+            return Err(XEngineError::GroupCommitWithoutWorker);
         }
 
-        self.offsets = offsets;
+        self.journal = Some(Vec::new());
+        self.rollback = Some(VolumeRollback {
+            actual_size: self.actual_size,
+            data_head: self.data_head,
+            max_size: self.max_size,
+            layout: self.layout,
+            map_offsets_count: self.map_offsets_count,
+            map_offsets_tombstones: self.map_offsets_tombstones,
+            free_extents: self.free_extents.clone(),
+            held_extents: self.held_extents.len(),
+            unsynced_chunks: self.unsynced_chunks,
+            cached: HashMap::new(),
+        });
+
+        let result = op(self);
+        let records = self.journal.take().unwrap_or_default();
+        let rollback = self.rollback.take().unwrap_or_default();
+
+        // A failed operation leaves the metadata on disk untouched, the cached one is restored
+        let value = match result {
+            Ok(value) => value,
+            Err(err) => {
+                self.restore(rollback);
+                return Err(err);
+            }
+        };
 
-        return Ok(());
-    }
-}
+        if records.is_empty() {
+            return Ok(value);
+        }
 
-impl ChunksHandler for Volume {
-    fn get_max_size(&self) -> u64 {
-        return self.max_size;
+        if self.group_start.is_none() {
+            self.group_start = Some(Instant::now());
+        }
+        self.commit_group(file, records)?;
+
+        return Ok(value);
     }
-    
-    fn get_actual_size(&self) -> u64 {
-        return self.offsets.len() as u64;
+
+    fn push_pending(&mut self, records: Vec<JournalRecord>) {
+        // A later record covering the same bytes replaces the earlier one
+        for record in records {
+            self.pending
+                .retain(|pending| pending.offset != record.offset || pending.data.len() != record.data.len());
+            self.pending.push(record);
+        }
     }
 
-    fn get_chunk(&mut self, uuid: String) -> Option<&Chunk> {
-        // TODO Lettura del chunk in base all'offset
-        return self.chunks.get(&uuid);
+    fn restore(&mut self, rollback: VolumeRollback) {
+        self.actual_size = rollback.actual_size;
+        self.data_head = rollback.data_head;
+        self.max_size = rollback.max_size;
+        self.layout = rollback.layout;
+        self.map_offsets_count = rollback.map_offsets_count;
+        self.map_offsets_tombstones = rollback.map_offsets_tombstones;
+        self.free_extents = rollback.free_extents;
+        self.held_extents.truncate(rollback.held_extents);
+        self.unsynced_chunks = rollback.unsynced_chunks;
+
+        for (uid, (offset, meta, chunk)) in rollback.cached {
+            match offset {
+                Some(offset) => self.offsets.insert(uid.clone(), offset),
+                None => self.offsets.remove(&uid),
+            };
+            match meta {
+                Some(meta) => self.chunks_meta.insert(uid.clone(), meta),
+                None => self.chunks_meta.remove(&uid),
+            };
+            match chunk {
+                Some(chunk) => self.chunks.insert(uid, chunk),
+                None => self.chunks.remove(&uid),
+            };
+        }
     }
 
-    fn add_chunk(&mut self, chunk: Chunk) -> Option<String> {
-        let chunk_uid = chunk.uid.clone();
-        self.chunks.insert(chunk_uid, chunk);
+    // Saves a cached entry before the running transaction first changes it
+    fn save_cached(&mut self, uid: &str) {
+        let Some(rollback) = self.rollback.as_mut() else {
+            return;
+        };
 
-        return Some(self.uid.clone());
+        if !rollback.cached.contains_key(uid) {
+            let saved = (
+                self.offsets.get(uid).copied(),
+                self.chunks_meta.get(uid).copied(),
+                self.chunks.get(uid).cloned(),
+            );
+            rollback.cached.insert(uid.to_string(), saved);
+        }
     }
 
-    fn is_full(self) -> bool {
-        return self.chunks.len() >= self.max_size as usize;
-    }
+    // Syncs the committed writes once the durability level asks for it
+    fn commit_group(&mut self, file: &File, records: Vec<JournalRecord>) -> Result<(), XEngineError> {
+        let due = match self.durability {
+            Durability::PerChunk => true,
+            Durability::GroupCommit { interval_ms, chunks } => {
+                let expired = self
+                    .group_start
+                    .is_some_and(|start| start.elapsed() >= Duration::from_millis(interval_ms));
 
-    fn get_chunk_v2(&mut self, file: &File, uuid: String) -> Result<Option<Chunk>, XEngineError> {
-        let offset = self.offsets.get(&uuid);
+                self.unsynced_chunks >= chunks || expired
+            }
+            Durability::None | Durability::OnHeaderFlush => false,
+        };
 
-        if offset.is_none() {
-            return Ok(None);
-        } else {
-            let offset = offset.unwrap();
-            let chunk_len = offset.end - offset.start;
-            let mut buf = vec![0u8; chunk_len as usize];
-            file.read_at(buf.as_mut_slice(), offset.start).unwrap();
+        let pending_len: usize = self
+            .pending
+            .iter()
+            .chain(records.iter())
+            .map(|record| record.data.len())
+            .sum();
 
-            let chunk = Chunk {
-                uid: uuid,
-                data: buf,
-                length: Some(chunk_len as usize),
-            };
+        // Left unsynced, the journal only survives a crashed process, which replays it on open
+        let appended = !due
+            && pending_len <= PENDING_JOURNAL_MAX_LEN
+            && append_journal(&journal_path(&self.path), &records).is_ok();
+
+        self.push_pending(records);
 
-            return Ok(Some(chunk));
+        // A journal left torn by a failed append is rewritten by the flush
+        if !appended {
+            return self.flush(file);
         }
+
+        return Ok(());
     }
 
-    fn add_chunk_v2(&mut self, file: &File, chunk: Chunk) -> Result<Option<String>, XEngineError> {
-        let max_size = self.get_max_size();
-        let actual_size = self.get_actual_size();
+    pub fn has_unsynced(&self) -> bool {
+        return self.group_start.is_some();
+    }
 
-        assert!(actual_size + 1 <= max_size, "Can't add others chunks to the handler: actual_size + 1 > max_size ({} + 1 > {})", actual_size, max_size);
-        
-        let chunk_uid = chunk.uid.clone();
+    // Start of the open group, the queue worker syncs it once its interval expires
+    pub(crate) fn unsynced_since(&self) -> Option<Instant> {
+        return self.group_start;
+    }
 
-        let offsets = self.offsets.clone();
-        let head_chunks = offsets
-            .values()
-            .map(|x| x.clone().end)
-            .max()
-            .unwrap_or(MAP_OFFSETS_START_OFFSET);
+    /*
+        Syncs every committed write regardless of the durability level.
 
-        let chunk_offset = ChunkOffset {
-            start: head_chunks,
-            end: head_chunks + chunk.data.len() as u64,
-        };
+        The pending metadata reaches the volume file only here, after the chunk data
+        and its journal are synced, so a power loss leaves the volume as it was at
+        the last flush or replays the whole journal on open
+    */
+    pub fn flush(&mut self, file: &File) -> Result<(), XEngineError> {
+        // The held extents are freed by the same journal as the metadata releasing them
+        let held_extents = std::mem::take(&mut self.held_extents);
+
+        if !held_extents.is_empty() {
+            self.journal = Some(Vec::new());
+            let result = held_extents.into_iter().try_for_each(|extent| self.free_extent(file, extent));
+            let records = self.journal.take().unwrap_or_default();
 
-        //TODO Update offset map on disk and update actual size on disk
-        self.offsets.insert(chunk_uid.clone(), chunk_offset);
+            result?;
+            self.push_pending(records);
+        }
 
-        file.write_all_at(&chunk.data, head_chunks).unwrap();
+        if (self.data_dirty || self.pending.is_empty())
+            && let Err(err) = file.sync_data()
+        {
+            return Err(XEngineError::IO(err));
+        }
+        self.data_dirty = false;
 
-        self.chunks.insert(chunk_uid, chunk);
+        if !self.pending.is_empty() {
+            let path = journal_path(&self.path);
 
-        return Ok(Some(self.uid.clone()));
+            write_journal_with(&path, &self.pending, true)?;
+            apply_journal_with(file, &self.pending, true)?;
+            clear_journal(&path)?;
+
+            self.pending.clear();
+        }
+
+        self.unsynced_chunks = 0;
+        self.group_start = None;
+
+        return Ok(());
+    }
+
+    pub fn recover_journal(&self) -> Result<bool, XEngineError> {
+        let path = journal_path(&self.path);
+        let records = read_journal(&path)?;
+
+        // Replaying writes the volume file, which a reader shares with other processes
+        if !self.writable {
+            if records.is_some() {
+                return Err(XEngineError::JournalPending);
+            }
+            return Ok(false);
+        }
+
+        // Only a complete journal is replayed, a torn one was never applied
+        let replayed = if let Some(records) = records {
+            let file = OpenOptions::new().write(true).open(&self.path);
+
+            if let Err(err) = file {
+                return Err(XEngineError::IO(err));
+            }
+
+            apply_journal(&file.unwrap(), &records)?;
+            true
+        } else {
+            false
+        };
+
+        clear_journal(&path)?;
+
+        return Ok(replayed);
+    }
+
+    fn read_superblock(&self, file: &File) -> Result<Vec<u8>, XEngineError> {
+        let config = get_bincode_config();
+
+        let mut buf = vec![0u8; SUPERBLOCK_LEN as usize];
+        if let Err(err) = file.read_exact_at(&mut buf, 0) {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                return Err(XEngineError::NotAVolume);
+            }
+            return Err(XEngineError::IO(err));
+        }
+
+        if buf[OFFSET_MAGIC as usize..(OFFSET_MAGIC + MAGIC_LEN) as usize] != VOLUME_MAGIC {
+            return Err(XEngineError::NotAVolume);
+        }
+
+        let format_version_bytes =
+            &buf[OFFSET_FORMAT_VERSION as usize..(OFFSET_FORMAT_VERSION + FORMAT_VERSION_LEN) as usize];
+        let format_version = decode_number(format_version_bytes, &config)?;
+
+        if format_version != VOLUME_FORMAT_VERSION {
+            return Err(XEngineError::UnsupportedVersion(format_version));
+        }
+
+        let checksum_bytes = &buf[OFFSET_HEADER_CHECKSUM as usize..SUPERBLOCK_LEN as usize];
+        let checksum = decode_number(checksum_bytes, &config)?;
+
+        if checksum != compute_checksum(&buf[..OFFSET_HEADER_CHECKSUM as usize]) {
+            return Err(XEngineError::CorruptHeader);
+        }
+
+        let features_bytes = &buf[OFFSET_FEATURES as usize..(OFFSET_FEATURES + FEATURES_LEN) as usize];
+        let features = decode_number(features_bytes, &config)?;
+
+        if features & !VOLUME_FEATURES != 0 {
+            return Err(XEngineError::UnsupportedFeatures(features));
+        }
+
+        return Ok(buf);
+    }
+
+    pub fn read_headers(&mut self, file: &mut File, cached: bool) -> Result<(), XEngineError> {
+        let config = get_bincode_config();
+
+        // The metadata not yet synced is applied before the volume file is read back
+        if !self.pending.is_empty() || !self.held_extents.is_empty() {
+            self.flush(file)?;
+        }
+
+        let replayed = !self.path.is_empty() && self.recover_journal()?;
+        self.pending.clear();
+
+        let buf = self.read_superblock(file)?;
+
+        let volume_uid_bytes = buf
+            [OFFSET_VOLUME_UID as usize..(OFFSET_VOLUME_UID + UID_LEN) as usize]
+            .try_into()
+            .unwrap();
+        let volume_uid = decode_uuid_to_string(volume_uid_bytes);
+        self.set_uid(volume_uid);
+
+        let max_size_bytes =
+            &buf[OFFSET_MAX_SIZE as usize..(OFFSET_MAX_SIZE + MAX_SIZE_LEN) as usize];
+        let max_size = decode_number(max_size_bytes, &config).unwrap();
+        self.set_max_size(max_size);
+
+        let actual_size_bytes =
+            &buf[OFFSET_ACTUAL_SIZE as usize..(OFFSET_ACTUAL_SIZE + ACTUAL_SIZE_LEN) as usize];
+        let actual_size: u64 = decode_number(actual_size_bytes, &config).unwrap();
+
+        let map_offsets_start_bytes = &buf
+            [OFFSET_MAP_OFFSETS_START as usize..(OFFSET_MAP_OFFSETS_START + MAP_OFFSETS_START_LEN) as usize];
+        let map_offsets_count_bytes = &buf
+            [OFFSET_MAP_OFFSETS_COUNT as usize..(OFFSET_MAP_OFFSETS_COUNT + MAP_OFFSETS_COUNT_LEN) as usize];
+        let map_offsets_tombstones_bytes = &buf
+            [OFFSET_MAP_OFFSETS_TOMBSTONES as usize..(OFFSET_MAP_OFFSETS_TOMBSTONES + MAP_OFFSETS_TOMBSTONES_LEN) as usize];
+        let free_extents_start_bytes = &buf
+            [OFFSET_FREE_EXTENTS_START as usize..(OFFSET_FREE_EXTENTS_START + FREE_EXTENTS_START_LEN) as usize];
+        let free_extents_count_bytes = &buf
+            [OFFSET_FREE_EXTENTS_COUNT as usize..(OFFSET_FREE_EXTENTS_COUNT + FREE_EXTENTS_COUNT_LEN) as usize];
+        let data_start_bytes =
+            &buf[OFFSET_DATA_START as usize..(OFFSET_DATA_START + DATA_START_LEN) as usize];
+        let data_end_bytes =
+            &buf[OFFSET_DATA_END as usize..(OFFSET_DATA_END + DATA_END_LEN) as usize];
+        let data_head_bytes =
+            &buf[OFFSET_DATA_HEAD as usize..(OFFSET_DATA_HEAD + DATA_HEAD_LEN) as usize];
+        let chunk_size_bytes =
+            &buf[OFFSET_CHUNK_SIZE as usize..(OFFSET_CHUNK_SIZE + CHUNK_SIZE_LEN) as usize];
+
+        let layout = VolumeLayout {
+            map_offsets_start: decode_number(map_offsets_start_bytes, &config)?,
+            free_extents_start: decode_number(free_extents_start_bytes, &config)?,
+            data_start: decode_number(data_start_bytes, &config)?,
+            data_end: decode_number(data_end_bytes, &config)?,
+        };
+
+        let map_offsets_count = decode_number(map_offsets_count_bytes, &config)?;
+        let map_offsets_tombstones = decode_number(map_offsets_tombstones_bytes, &config)?;
+        let free_extents_count = decode_number(free_extents_count_bytes, &config)?;
+        let data_head = decode_number(data_head_bytes, &config)?;
+        let chunk_size = decode_number(chunk_size_bytes, &config)?;
+
+        let key_slots = VolumeKeySlots {
+            salt: buf[OFFSET_KEY_SALT as usize..(OFFSET_KEY_SALT + KEY_SALT_LEN) as usize].try_into().unwrap(),
+            current: buf[OFFSET_KEY_CURRENT as usize..(OFFSET_KEY_CURRENT + KEY_CURRENT_LEN) as usize].try_into().unwrap(),
+            previous: buf[OFFSET_KEY_PREVIOUS as usize..(OFFSET_KEY_PREVIOUS + KEY_PREVIOUS_LEN) as usize].try_into().unwrap(),
+        };
+
+        if !is_valid_chunk_size(chunk_size as usize) {
+            return Err(XEngineError::InvalidChunkSize(chunk_size));
+        }
+
+        // Offset map, free extents and chunks data must never overlap
+        if actual_size > max_size
+            || max_size > map_offsets_count
+            || actual_size + map_offsets_tombstones > map_offsets_count
+            || free_extents_count > max_size
+            || layout.map_offsets_start < SUPERBLOCK_LEN
+            || layout.map_offsets_end(map_offsets_count) > layout.free_extents_start
+            || layout.free_extents_end(max_size) > layout.data_start
+            || layout.data_start > layout.data_end
+            || data_head > layout.data_end
+        {
+            return Err(XEngineError::InvalidVolumeLayout);
+        }
+
+        self.layout = layout;
+        self.map_offsets_count = map_offsets_count;
+        self.map_offsets_tombstones = map_offsets_tombstones;
+        self.actual_size = actual_size;
+        self.data_head = data_head;
+        self.chunk_size = chunk_size;
+        self.key_slots = key_slots;
+        self.cached = cached;
+
+        self.offsets = VolumeOffsets::new();
+        self.chunks_meta = VolumeChunksMeta::new();
+        self.chunks = VolumeChunks::new();
+
+        if cached {
+            for (_, entry) in self.read_offset_map_entries(file)? {
+                self.chunks_meta.insert(entry.uid.clone(), entry.meta);
+                self.offsets.insert(entry.uid, entry.offset);
+            }
+        }
+
+        let mut free_buf = vec![0u8; (free_extents_count * FREE_EXTENTS_ELEM_LEN) as usize];
+        if let Err(err) = file.read_exact_at(&mut free_buf, layout.free_extents_start) {
+            return Err(XEngineError::IO(err));
+        }
+
+        let mut free_extents = Vec::with_capacity(free_extents_count as usize);
+
+        for free_elem_bytes in free_buf.chunks_exact(FREE_EXTENTS_ELEM_LEN as usize) {
+            free_extents.push(parse_chunk_offset(free_elem_bytes, config)?);
+        }
+
+        self.free_extents = free_extents;
+
+        // Extents held until a flush that never ran were only freed in memory,
+        // they are found again between the map entries
+        if replayed {
+            self.rebuild_free_extents(file)?;
+        }
+
+        return Ok(());
+    }
+
+    fn write_offset_map_elem(&mut self, file: &File, slot: u64, uid: String, offset: &ChunkOffset, flags: u64, meta: &ChunkMeta) -> Result<(), XEngineError> {
+        let config = get_bincode_config();
+        let elem_bytes = encode_offset_map_elem(uid, offset, flags, meta, config)?;
+        let elem_offset = self.layout.map_offsets_start + (slot * MAP_OFFSETS_ELEM_LEN);
+
+        if slot >= self.map_offsets_count {
+            return Err(XEngineError::InvalidVolumeLayout);
+        }
+
+        return self.write_meta(file, &elem_bytes, elem_offset);
+    }
+
+    fn read_offset_map_elem(&self, file: &File, slot: u64) -> Result<ParseOffsetMapElem, XEngineError> {
+        let config = get_bincode_config();
+
+        let mut buf = [0u8; MAP_OFFSETS_ELEM_LEN as usize];
+        let elem_offset = self.layout.map_offsets_start + (slot * MAP_OFFSETS_ELEM_LEN);
+
+        if let Err(err) = file.read_exact_at(&mut buf, elem_offset) {
+            return Err(XEngineError::IO(err));
+        }
+        self.overlay_journal(&mut buf, elem_offset);
+
+        return parse_offset_map_elem(&buf, config);
+    }
+
+    // Linear probing from the home slot of the uid, an empty slot ends the probe sequence
+    // while a tombstone keeps it going
+    fn find_offset_map_elem(&self, file: &File, chunk_uid: &str) -> Result<Option<(u64, ParseOffsetMapElem)>, XEngineError> {
+        let home = map_offsets_home_slot(chunk_uid, self.map_offsets_count)?;
+
+        for probe in 0..self.map_offsets_count {
+            let slot = (home + probe) % self.map_offsets_count;
+            let result = self.read_offset_map_elem(file, slot)?;
+
+            if result.flags == MAP_OFFSETS_ELEM_EMPTY {
+                return Ok(None);
+            }
+
+            if result.flags & MAP_OFFSETS_ELEM_LIVE != 0 && result.uid == chunk_uid {
+                return Ok(Some((slot, result)));
+            }
+        }
+
+        return Ok(None);
+    }
+
+    // The slot holding the uid, otherwise the first reusable slot on its probe sequence,
+    // and whether that slot holds a tombstone
+    fn find_free_offset_map_slot(&self, file: &File, chunk_uid: &str) -> Result<(u64, bool), XEngineError> {
+        let home = map_offsets_home_slot(chunk_uid, self.map_offsets_count)?;
+        let mut tombstone = None;
+
+        for probe in 0..self.map_offsets_count {
+            let slot = (home + probe) % self.map_offsets_count;
+            let result = self.read_offset_map_elem(file, slot)?;
+
+            if result.flags == MAP_OFFSETS_ELEM_EMPTY {
+                return Ok(tombstone.map_or((slot, false), |tombstone| (tombstone, true)));
+            }
+
+            if result.flags & MAP_OFFSETS_ELEM_LIVE != 0 {
+                if result.uid == chunk_uid {
+                    return Ok((slot, false));
+                }
+            } else if tombstone.is_none() {
+                tombstone = Some(slot);
+            }
+        }
+
+        let Some(slot) = tombstone else {
+            return Err(XEngineError::VolumeFull);
+        };
+
+        return Ok((slot, true));
+    }
+
+    fn read_offset_map_entries(&self, file: &File) -> Result<Vec<(u64, ParseOffsetMapElem)>, XEngineError> {
+        let config = get_bincode_config();
+
+        let mut map_buf = vec![0u8; (self.map_offsets_count * MAP_OFFSETS_ELEM_LEN) as usize];
+        if let Err(err) = file.read_exact_at(&mut map_buf, self.layout.map_offsets_start) {
+            return Err(XEngineError::IO(err));
+        }
+        self.overlay_journal(&mut map_buf, self.layout.map_offsets_start);
+
+        let mut entries = Vec::with_capacity(self.actual_size as usize);
+
+        for (slot, map_elem_bytes) in map_buf.chunks_exact(MAP_OFFSETS_ELEM_LEN as usize).enumerate() {
+            let result = parse_offset_map_elem(map_elem_bytes, config)?;
+
+            if result.flags & MAP_OFFSETS_ELEM_LIVE != 0 {
+                entries.push((slot as u64, result));
+            }
+        }
+
+        return Ok(entries);
+    }
+
+    fn lookup_chunk(&self, file: &File, chunk_uid: &str) -> Result<Option<(ChunkOffset, ChunkMeta)>, XEngineError> {
+        if self.cached {
+            let Some(offset) = self.offsets.get(chunk_uid).copied() else {
+                return Ok(None);
+            };
+            let meta = self.chunks_meta.get(chunk_uid).copied().unwrap_or_default();
+
+            return Ok(Some((offset, meta)));
+        }
+
+        let result = self.find_offset_map_elem(file, chunk_uid)?;
+
+        return Ok(result.map(|(_, entry)| (entry.offset, entry.meta)));
+    }
+
+    fn write_free_extents(&mut self, file: &File, from: usize, to: usize) -> Result<(), XEngineError> {
+        let config = get_bincode_config();
+        let to = to.min(self.free_extents.len());
+
+        if from >= to {
+            return Ok(());
+        }
+
+        let mut buf = Vec::with_capacity((to - from) * FREE_EXTENTS_ELEM_LEN as usize);
+
+        for extent in self.free_extents[from..to].iter() {
+            buf.extend_from_slice(&encode_chunk_offset(extent, config)?);
+        }
+
+        let free_offset = self.layout.free_extents_start + (from as u64 * FREE_EXTENTS_ELEM_LEN);
+
+        return self.write_meta(file, &buf, free_offset);
+    }
+
+    pub fn get_data_head(&self) -> u64 {
+        return self.data_head.max(self.layout.data_start);
+    }
+
+    /*
+        First fit on the free extents, otherwise append after the data head.
+
+        Compressed and encrypted chunks have different lengths, so the free space can
+        be enough for a chunk while no free extent fits it. The volume is then
+        fragmented rather than full, and a compaction merges its free space
+    */
+    fn alloc_extent(&mut self, file: &File, len: u64) -> Result<ChunkOffset, XEngineError> {
+        let index = self.free_extents.iter().position(|x| x.len() >= len);
+
+        if let Some(index) = index {
+            let free = self.free_extents[index];
+            let extent = ChunkOffset {
+                start: free.start,
+                end: free.start + len,
+            };
+
+            if free.len() == len {
+                self.free_extents.swap_remove(index);
+            } else {
+                self.free_extents[index].start += len;
+            }
+
+            self.write_free_extents(file, index, index + 1)?;
+            self.write_header(file)?;
+
+            return Ok(extent);
+        }
+
+        let head = self.get_data_head();
+        let extent = ChunkOffset {
+            start: head,
+            end: head + len,
+        };
+
+        if !self.layout.contains(&extent) {
+            let free_len = self.free_extents.iter().map(|x| x.len()).sum::<u64>() + self.layout.data_end.saturating_sub(head);
+
+            if free_len >= len {
+                return Err(XEngineError::VolumeFragmented);
+            }
+            return Err(XEngineError::VolumeFull);
+        }
+
+        // The header with the new data head is written together with the chunk map entry
+        self.data_head = extent.end;
+
+        return Ok(extent);
+    }
+
+    fn release_extent(&mut self, file: &File, extent: ChunkOffset) -> Result<(), XEngineError> {
+        // Freed by finish_compaction, which rebuilds the free extents from the map
+        if extent.is_empty() || self.compacting {
+            return Ok(());
+        }
+
+        // Reusing the extent before the flush could overwrite data the volume file still references
+        if self.durability != Durability::PerChunk {
+            self.held_extents.push(extent);
+            return Ok(());
+        }
+
+        return self.free_extent(file, extent);
+    }
+
+    fn free_extent(&mut self, file: &File, extent: ChunkOffset) -> Result<(), XEngineError> {
+        // Already freed by a rebuild of the free extents
+        if extent.start >= self.get_data_head() || self.free_extents.iter().any(|free| free.start <= extent.start && extent.end <= free.end) {
+            return Ok(());
+        }
+
+        let mut extent = extent;
+        let mut from = self.free_extents.len();
+        let mut index = 0;
+
+        // Coalesce with the adjacent free extents
+        while index < self.free_extents.len() {
+            let free = self.free_extents[index];
+
+            if free.end == extent.start || free.start == extent.end {
+                extent.start = extent.start.min(free.start);
+                extent.end = extent.end.max(free.end);
+
+                self.free_extents.swap_remove(index);
+                from = from.min(index);
+            } else {
+                index += 1;
+            }
+        }
+
+        // A full free list is rebuilt from the gaps between the map entries, which include the extent
+        if self.free_extents.len() as u64 >= self.max_size {
+            return self.rebuild_free_extents(file);
+        }
+        self.free_extents.push(extent);
+
+        self.write_free_extents(file, from, self.free_extents.len())?;
+        self.write_header(file)?;
+
+        return Ok(());
+    }
+
+    // Lookups and positional reads only, so chunks can be read through a shared reference
+    pub fn read_chunk(&self, file: &File, uuid: String) -> Result<Option<Chunk>, XEngineError> {
+        let chunk = self.lookup_chunk(file, &uuid)?;
+
+        if chunk.is_none() {
+            return Ok(None);
+        } else {
+            let (offset, meta) = chunk.unwrap();
+
+            if !self.layout.contains(&offset) {
+                return Err(XEngineError::ChunkOutOfBounds(uuid));
+            }
+
+            let chunk_len = offset.end - offset.start;
+            let mut buf = vec![0u8; chunk_len as usize];
+
+            if let Err(err) = file.read_exact_at(buf.as_mut_slice(), offset.start) {
+                return Err(XEngineError::IO(err));
+            }
+
+            return Ok(Some(self.verify_chunk(uuid, &meta, &buf)?));
+        }
+    }
+
+    // Looks up every chunk first, then reads all the found ones in a single batch ordered by offset
+    pub fn read_chunks(&self, file: &File, uuids: &[String]) -> Result<Vec<Option<Chunk>>, XEngineError> {
+        let mut located = Vec::new();
+
+        for (index, uuid) in uuids.iter().enumerate() {
+            let Some((offset, meta)) = self.lookup_chunk(file, uuid)? else {
+                continue;
+            };
+
+            if !self.layout.contains(&offset) {
+                return Err(XEngineError::ChunkOutOfBounds(uuid.clone()));
+            }
+
+            located.push((index, offset, meta));
+        }
+
+        located.sort_by_key(|(_, offset, _)| offset.start);
+
+        let mut found = Vec::with_capacity(located.len());
+        let mut reads = Vec::with_capacity(located.len());
+
+        for (index, offset, meta) in located {
+            found.push((index, meta));
+            reads.push(ReadRequest {
+                offset: offset.start,
+                buf: vec![0u8; offset.len() as usize],
+            });
+        }
+
+        read_batch(self.io_engine, file, &mut reads)?;
+
+        let mut chunks = vec![None; uuids.len()];
+
+        for ((index, meta), read) in found.into_iter().zip(reads) {
+            chunks[index] = Some(self.verify_chunk(uuids[index].clone(), &meta, &read.buf)?);
+        }
+
+        return Ok(chunks);
+    }
+
+    fn verify_chunk(&self, uuid: String, meta: &ChunkMeta, stored: &[u8]) -> Result<Chunk, XEngineError> {
+        // Bit rot and torn writes are detected before the data leaves the volume
+        if compute_checksum(stored) != meta.checksum {
+            return Err(XEngineError::ChecksumMismatch {
+                chunk_uid: uuid,
+                volume_uid: self.uid.clone(),
+            });
+        }
+
+        let data = self.decode_chunk(&uuid, meta, stored)?;
+        let chunk_len = data.len();
+
+        return Ok(Chunk {
+            uid: uuid,
+            data,
+            length: Some(chunk_len),
+        });
+    }
+
+    // The raw checksum only filters candidates, a collision must not keep the old data
+    fn stores_data(&self, file: &File, uuid: &str, offset: &ChunkOffset, meta: &ChunkMeta, data: &[u8]) -> Result<bool, XEngineError> {
+        if !self.layout.contains(offset) {
+            return Ok(false);
+        }
+
+        let deferred = self
+            .batch
+            .as_ref()
+            .and_then(|batch| batch.writes.iter().rev().find(|write| write.offset == offset.start));
+
+        let stored = match deferred {
+            Some(write) => write.data.clone(),
+            None => {
+                let mut buf = vec![0u8; offset.len() as usize];
+
+                if let Err(err) = file.read_exact_at(buf.as_mut_slice(), offset.start) {
+                    return Err(XEngineError::IO(err));
+                }
+                buf
+            }
+        };
+
+        // A chunk that no longer verifies is replaced by the new data
+        match self.verify_chunk(uuid.to_string(), meta, &stored) {
+            Ok(old) => return Ok(old.data == data),
+            Err(_) => return Ok(false),
+        }
+    }
+
+    pub fn contains_chunk(&self, file: &File, uuid: &str) -> Result<bool, XEngineError> {
+        return Ok(self.lookup_chunk(file, uuid)?.is_some());
+    }
+
+    pub fn get_chunk_refcount(&self, file: &File, uuid: &str) -> Result<Option<u64>, XEngineError> {
+        return Ok(self.lookup_chunk(file, uuid)?.map(|(_, meta)| meta.refcount));
+    }
+
+    // Drops one reference to every chunk of the file, chunks shared with other files stay stored.
+    // Each chunk is its own transaction since lookups read the offset map from disk
+    pub fn remove_file_chunks(&mut self, file: &File, xfile: &XFile) -> Result<(), XEngineError> {
+        for chunk_uid in xfile.chunk_uids.iter() {
+            self.remove_chunk_v2(file, chunk_uid.clone())?;
+        }
+
+        return Ok(());
+    }
+
+    pub fn compact(&mut self, file: &File, live_chunks: &HashSet<String>) -> Result<u64, XEngineError> {
+        let mut compaction = self.begin_compaction(file, live_chunks)?;
+        let mut result = Ok(());
+
+        while let Some(relocation) = compaction.next_relocation() {
+            result = self
+                .copy_relocation(file, &relocation)
+                .and_then(|_| self.apply_relocation(file, &relocation).map(|_| ()));
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        // The free extents are rebuilt even when a relocation failed
+        let reclaimed = self.finish_compaction(file, compaction);
+        result?;
+
+        return reclaimed;
+    }
+
+    /*
+        Compaction in steps, so a shared volume only holds the write lock to update
+        each map entry and stays readable while the chunk data is copied.
+
+        Until the compaction finishes nothing is added to the free extents, so new
+        chunks are appended after the data head and the relocation targets, below
+        the chunks they come from, never hold live data.
+    */
+    pub fn begin_compaction(&mut self, file: &File, live_chunks: &HashSet<String>) -> Result<VolumeCompaction, XEngineError> {
+        if self.compacting {
+            return Err(XEngineError::CompactionInProgress);
+        }
+
+        let old_head = self.get_data_head();
+
+        let dead_chunks: Vec<(u64, ParseOffsetMapElem)> = self
+            .read_offset_map_entries(file)?
+            .into_iter()
+            .filter(|(_, entry)| !live_chunks.contains(&entry.uid))
+            .collect();
+
+        // Dead chunks go whatever their refcount, no live file references them.
+        // The slots collected above hold until the last purge, the map is rehashed after it
+        self.transaction(file, |volume| {
+            for (slot, entry) in dead_chunks {
+                volume.purge_chunk(file, slot, entry)?;
+            }
+            return volume.rehash_tombstones(file);
+        })?;
+
+        // Relocation targets must not hold data the volume file still references
+        if !self.pending.is_empty() {
+            self.flush(file)?;
+        }
+
+        // An interrupted compaction only leaks space
+        self.transaction(file, |volume| {
+            volume.free_extents.clear();
+            return volume.write_header(file);
+        })?;
+        self.compacting = true;
+
+        let mut chunks: Vec<Relocation> = self
+            .read_offset_map_entries(file)?
+            .into_iter()
+            .map(|(slot, entry)| Relocation {
+                slot,
+                uid: entry.uid,
+                offset: entry.offset,
+                meta: entry.meta,
+                target: entry.offset,
+            })
+            .collect();
+        chunks.sort_by_key(|chunk| chunk.offset.start);
+        chunks.reverse();
+
+        return Ok(VolumeCompaction {
+            chunks,
+            cursor: self.layout.data_start,
+            old_head,
+        });
+    }
+
+    // Positional I/O only, the target is never read before its map entry is updated
+    pub fn copy_relocation(&self, file: &File, relocation: &Relocation) -> Result<(), XEngineError> {
+        let mut buf = vec![0u8; relocation.offset.len() as usize];
+
+        if let Err(err) = file.read_exact_at(&mut buf, relocation.offset.start) {
+            return Err(XEngineError::IO(err));
+        }
+
+        if let Err(err) = file.write_all_at(&buf, relocation.target.start) {
+            return Err(XEngineError::IO(err));
+        }
+
+        return Ok(());
+    }
+
+    // Points the map entry to the copy, unless the chunk changed since the compaction began
+    pub fn apply_relocation(&mut self, file: &File, relocation: &Relocation) -> Result<bool, XEngineError> {
+        let Some((slot, entry)) = self.find_offset_map_elem(file, &relocation.uid)? else {
+            return Ok(false);
+        };
+
+        if slot != relocation.slot || entry.offset != relocation.offset || entry.meta.checksum != relocation.meta.checksum {
+            return Ok(false);
+        }
+
+        // The copy was written outside write_data
+        self.data_dirty = true;
+
+        self.transaction(file, |volume| {
+            return volume.write_offset_map_elem(file, slot, relocation.uid.clone(), &relocation.target, MAP_OFFSETS_ELEM_LIVE, &entry.meta);
+        })?;
+
+        if let Some(cached) = self.offsets.get_mut(&relocation.uid) {
+            *cached = relocation.target;
+        }
+
+        // A later relocation may target the extent this chunk leaves
+        if !self.pending.is_empty() {
+            self.flush(file)?;
+        }
+
+        return Ok(true);
+    }
+
+    // Rebuilds the free extents and the data head from the map entries, returns the reclaimed bytes
+    pub fn finish_compaction(&mut self, file: &File, compaction: VolumeCompaction) -> Result<u64, XEngineError> {
+        self.compacting = false;
+
+        // The extents left by the relocations are freed once the volume file no longer references them
+        if !self.pending.is_empty() {
+            self.flush(file)?;
+        }
+
+        self.rebuild_free_extents(file)?;
+
+        let new_head = self.get_data_head();
+
+        return Ok(compaction.old_head.saturating_sub(new_head));
+    }
+
+    // Frees all the space between the map entries, data_head ends at the last one.
+    // Held extents are still referenced by the volume file and stay allocated
+    fn rebuild_free_extents(&mut self, file: &File) -> Result<(), XEngineError> {
+        let extents: Vec<ChunkOffset> = self
+            .read_offset_map_entries(file)?
+            .into_iter()
+            .map(|(_, entry)| entry.offset)
+            .chain(self.held_extents.iter().copied())
+            .collect();
+
+        let (mut gaps, data_head) = extent_gaps(extents, self.layout.data_start);
+        gaps.truncate(self.max_size as usize);
+
+        return self.transaction(file, |volume| {
+            volume.free_extents = gaps;
+            volume.data_head = data_head;
+            volume.write_free_extents(file, 0, volume.free_extents.len())?;
+            return volume.write_header(file);
+        });
+    }
+
+    pub fn scrub(&self, file: &File) -> Result<ScrubReport, XEngineError> {
+        let config = get_bincode_config();
+        let mut report = ScrubReport::default();
+
+        let file_len = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(err) => return Err(XEngineError::IO(err)),
+        };
+
+        // The table on disk is checked, not the in memory offsets, with the entries not yet synced
+        let mut map_buf = vec![0u8; (self.map_offsets_count * MAP_OFFSETS_ELEM_LEN) as usize];
+        if let Err(err) = file.read_exact_at(&mut map_buf, self.layout.map_offsets_start) {
+            return Err(XEngineError::IO(err));
+        }
+        self.overlay_journal(&mut map_buf, self.layout.map_offsets_start);
+
+        let mut extents: Vec<(String, ChunkOffset)> = Vec::new();
+
+        for map_elem_bytes in map_buf.chunks_exact(MAP_OFFSETS_ELEM_LEN as usize) {
+            let result = parse_offset_map_elem(map_elem_bytes, config)?;
+
+            if result.flags & MAP_OFFSETS_ELEM_LIVE == 0 {
+                continue;
+            }
+
+            let offset = result.offset;
+
+            if !self.layout.contains(&offset) || offset.end > file_len {
+                report.out_of_bounds.push(result.uid);
+                continue;
+            }
+
+            let mut buf = vec![0u8; offset.len() as usize];
+            if let Err(err) = file.read_exact_at(&mut buf, offset.start) {
+                return Err(XEngineError::IO(err));
+            }
+            self.overlay_journal(&mut buf, offset.start);
+
+            if compute_checksum(&buf) == result.meta.checksum {
+                report.ok.push(result.uid.clone());
+            } else {
+                report.corrupt.push(result.uid.clone());
+            }
+
+            extents.push((result.uid, offset));
+        }
+
+        extents.sort_by_key(|(_, offset)| offset.start);
+
+        // Each extent is compared with the one reaching further among the previous ones
+        let mut furthest: Option<&(String, ChunkOffset)> = None;
+
+        for extent in extents.iter() {
+            if let Some(previous) = furthest
+                && previous.1.end > extent.1.start
+                && !extent.1.is_empty()
+            {
+                report.overlapping.push((previous.0.clone(), extent.0.clone()));
+            }
+
+            if furthest.is_none_or(|previous| extent.1.end > previous.1.end) {
+                furthest = Some(extent);
+            }
+        }
+
+        for (uid, offset) in extents.iter() {
+            let overlapped = self
+                .free_extents
+                .iter()
+                .any(|free| free.start < offset.end && offset.start < free.end);
+
+            if overlapped {
+                report.overlapping_free.push(uid.clone());
+            }
+        }
+
+        return Ok(report);
+    }
+
+    pub fn resize(&mut self, file: &File, new_max_size: u64) -> Result<(), XEngineError> {
+        assert!(new_max_size > 0, "Volume max_size cannot be 0");
+
+        if self.compacting {
+            return Err(XEngineError::CompactionInProgress);
+        }
+
+        // No extent held back may end up outside the resized data region
+        if !self.pending.is_empty() || !self.held_extents.is_empty() {
+            self.flush(file)?;
+        }
+
+        // A shrinking file must not be mapped, a growing one must be mapped again
+        #[cfg(feature = "mmap")]
+        let mapped = self.mmap.take().is_some();
+
+        let result = if new_max_size > self.max_size {
+            self.grow(file, new_max_size)
+        } else if new_max_size < self.max_size {
+            self.shrink(file, new_max_size)
+        } else {
+            Ok(())
+        };
+
+        #[cfg(feature = "mmap")]
+        if mapped {
+            self.map(file)?;
+        }
+
+        return result;
+    }
+
+    #[cfg(feature = "mmap")]
+    pub fn map(&mut self, file: &File) -> Result<(), XEngineError> {
+        self.mmap.set(VolumeMap::new(file)?);
+        return Ok(());
+    }
+
+    #[cfg(feature = "mmap")]
+    pub fn unmap(&mut self) {
+        self.mmap.take();
+    }
+
+    #[cfg(feature = "mmap")]
+    pub fn is_mapped(&self) -> bool {
+        return self.mmap.is_some();
+    }
+
+    // Raw chunks are borrowed from the mapping, so they cannot outlive a remapping,
+    // compressed or encrypted ones are decoded into a new buffer
+    #[cfg(feature = "mmap")]
+    pub fn get_chunk_mapped(&self, file: &File, uuid: &str) -> Result<Option<Cow<'_, [u8]>>, XEngineError> {
+        let Some(mmap) = self.mmap.get() else {
+            return Err(XEngineError::VolumeNotMapped);
+        };
+
+        let Some((offset, meta)) = self.lookup_chunk(file, uuid)? else {
+            return Ok(None);
+        };
+
+        if !self.layout.contains(&offset) {
+            return Err(XEngineError::ChunkOutOfBounds(uuid.to_string()));
+        }
+
+        let Some(data) = mmap.slice(&offset) else {
+            return Err(XEngineError::ChunkOutOfBounds(uuid.to_string()));
+        };
+
+        if compute_checksum(data) != meta.checksum {
+            return Err(XEngineError::ChecksumMismatch {
+                chunk_uid: uuid.to_string(),
+                volume_uid: self.uid.clone(),
+            });
+        }
+
+        if meta.codec != ChunkCodec::None || meta.cipher != ChunkCipher::None {
+            return Ok(Some(Cow::Owned(self.decode_chunk(uuid, &meta, data)?)));
+        }
+
+        return Ok(Some(Cow::Borrowed(data)));
+    }
+
+    // Encrypts every chunk with a new data key, wrapped in the superblock by a master
+    // key derived from the passphrase. Only an empty volume can be encrypted, chunks
+    // already stored would otherwise stay plaintext
+    #[cfg(feature = "encryption")]
+    pub fn enable_encryption(&mut self, file: &File, passphrase: &[u8]) -> Result<(), XEngineError> {
+        if self.key_slots.is_encrypted() {
+            return Err(XEngineError::VolumeAlreadyEncrypted);
+        }
+
+        if self.get_actual_size() > 0 {
+            return Err(XEngineError::VolumeNotEmpty);
+        }
+
+        let salt = generate_salt();
+        let master = derive_master_key(passphrase, &salt)?;
+        let key = generate_key();
+
+        self.key_slots = VolumeKeySlots {
+            salt,
+            current: wrap_key(&master, &key, &self.uid)?,
+            previous: [0u8; WRAPPED_KEY_LEN],
+        };
+        self.transaction(file, |volume| volume.write_header(file))?;
+
+        self.key = Some(key);
+        self.previous_key = None;
+
+        return Ok(());
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn unlock(&mut self, passphrase: &[u8]) -> Result<(), XEngineError> {
+        let (_, key, previous_key) = self.unwrap_keys(passphrase)?;
+
+        self.key = Some(key);
+        self.previous_key = previous_key;
+
+        return Ok(());
+    }
+
+    // Rewraps the data keys with a new master key, chunks are left untouched
+    #[cfg(feature = "encryption")]
+    pub fn rotate_master_key(&mut self, file: &File, passphrase: &[u8], new_passphrase: &[u8]) -> Result<(), XEngineError> {
+        let (_, key, previous_key) = self.unwrap_keys(passphrase)?;
+
+        let salt = generate_salt();
+        let master = derive_master_key(new_passphrase, &salt)?;
+
+        let mut key_slots = VolumeKeySlots {
+            salt,
+            current: wrap_key(&master, &key, &self.uid)?,
+            previous: [0u8; WRAPPED_KEY_LEN],
+        };
+
+        if let Some(previous_key) = previous_key.as_ref() {
+            key_slots.previous = wrap_key(&master, previous_key, &self.uid)?;
+        }
+
+        self.key_slots = key_slots;
+        self.transaction(file, |volume| volume.write_header(file))?;
+
+        self.key = Some(key);
+        self.previous_key = previous_key;
+
+        return Ok(());
+    }
+
+    // Re-encrypts every encrypted chunk with a new data key, for a compromised data key.
+    // The previous key stays in the superblock until all chunks are re-encrypted,
+    // so an interrupted rotation is resumed by running it again
+    #[cfg(feature = "encryption")]
+    pub fn rotate_data_key(&mut self, file: &File, passphrase: &[u8]) -> Result<u64, XEngineError> {
+        let (master, key, previous_key) = self.unwrap_keys(passphrase)?;
+
+        let (key, previous_key) = match previous_key {
+            Some(previous_key) => (key, previous_key),
+            None => {
+                let new_key = generate_key();
+
+                self.key_slots.previous = self.key_slots.current;
+                self.key_slots.current = wrap_key(&master, &new_key, &self.uid)?;
+                self.transaction(file, |volume| volume.write_header(file))?;
+
+                (new_key, key)
+            }
+        };
+
+        self.key = Some(key);
+        self.previous_key = Some(previous_key);
+
+        let mut reencrypted = 0;
+
+        for (slot, entry) in self.read_offset_map_entries(file)? {
+            if self.reencrypt_chunk(file, slot, entry)? {
+                reencrypted += 1;
+            }
+        }
+
+        self.key_slots.previous = [0u8; WRAPPED_KEY_LEN];
+        self.transaction(file, |volume| volume.write_header(file))?;
+        self.previous_key = None;
+
+        // The chunks are read back from the volume file, where the new ciphertext is written by the flush
+        if !self.pending.is_empty() {
+            self.flush(file)?;
+        }
+
+        return Ok(reencrypted);
+    }
+
+    #[cfg(feature = "encryption")]
+    fn unwrap_keys(&self, passphrase: &[u8]) -> Result<(ChunkKey, ChunkKey, Option<ChunkKey>), XEngineError> {
+        if !self.key_slots.is_encrypted() {
+            return Err(XEngineError::VolumeNotEncrypted);
+        }
+
+        let master = derive_master_key(passphrase, &self.key_slots.salt)?;
+        let key = unwrap_key(&master, &self.key_slots.current, &self.uid)?;
+
+        let previous_key = if self.key_slots.has_previous() {
+            Some(unwrap_key(&master, &self.key_slots.previous, &self.uid)?)
+        } else {
+            None
+        };
+
+        return Ok((master, key, previous_key));
+    }
+
+    // Returns false for plaintext chunks and chunks already encrypted with the current key.
+    // The ciphertext keeps its length, so the chunk is rewritten in place and the journal
+    // makes data and entry atomic
+    #[cfg(feature = "encryption")]
+    fn reencrypt_chunk(&mut self, file: &File, slot: u64, entry: ParseOffsetMapElem) -> Result<bool, XEngineError> {
+        let Some(key) = self.key.clone() else {
+            return Err(XEngineError::EncryptionKeyRequired);
+        };
+
+        if entry.meta.cipher == ChunkCipher::None {
+            return Ok(false);
+        }
+
+        let offset = entry.offset;
+        let mut buf = vec![0u8; offset.len() as usize];
+
+        if let Err(err) = file.read_exact_at(&mut buf, offset.start) {
+            return Err(XEngineError::IO(err));
+        }
+
+        if compute_checksum(&buf) != entry.meta.checksum {
+            return Err(XEngineError::ChecksumMismatch {
+                chunk_uid: entry.uid,
+                volume_uid: self.uid.clone(),
+            });
+        }
+
+        if decrypt_chunk(&key, &entry.uid, &buf).is_ok() {
+            return Ok(false);
+        }
+
+        let Some(previous_key) = self.previous_key.as_ref() else {
+            return Err(XEngineError::EncryptionKeyRequired);
+        };
+
+        let encoded = decrypt_chunk(previous_key, &entry.uid, &buf)?;
+
+        let stored = encrypt_chunk(&key, &entry.uid, &encoded)?;
+        let meta = ChunkMeta {
+            checksum: compute_checksum(&stored),
+            cipher: ChunkCipher::ChaCha20Poly1305,
+            ..entry.meta
+        };
+
+        let uid = entry.uid;
+
+        self.transaction(file, |volume| {
+            volume.write_meta(file, &stored, offset.start)?;
+            return volume.write_offset_map_elem(file, slot, uid.clone(), &offset, MAP_OFFSETS_ELEM_LIVE, &meta);
+        })?;
+
+        if let Some(cached) = self.chunks_meta.get_mut(&uid) {
+            *cached = meta;
+        }
+
+        return Ok(true);
+    }
+
+    fn grow(&mut self, file: &File, new_max_size: u64) -> Result<(), XEngineError> {
+        let new_layout = VolumeLayout::from_max_size(new_max_size, self.chunk_size);
+        let new_slots = VolumeLayout::map_offsets_slots(new_max_size);
+
+        if let Err(err) = file.set_len(new_layout.data_end) {
+            return Err(XEngineError::IO(err));
+        }
+
+        // The data region is first extended in place, so the chunks evacuated from
+        // the space taken by the bigger offset map stay reachable with the old layout
+        let mut free_extents = self.transaction(file, |volume| {
+            volume.layout.data_end = new_layout.data_end;
+            volume.data_head = volume.get_data_head().max(new_layout.data_start);
+
+            let free_extents = std::mem::take(&mut volume.free_extents);
+            volume.write_header(file)?;
+            return Ok(free_extents);
+        })?;
+
+        let mut entries = self.read_offset_map_entries(file)?;
+        entries.sort_by_key(|(_, entry)| entry.offset.start);
+
+        for (slot, entry) in entries.iter_mut() {
+            let offset = entry.offset;
+
+            if offset.start >= new_layout.data_start {
+                continue;
+            }
+
+            // The free extents taken above are rebuilt with the old layout
+            if let Err(err) = self.evacuate_chunk(file, &new_layout, *slot, entry) {
+                self.rebuild_free_extents(file)?;
+                return Err(err);
+            }
+
+            free_extents.push(offset);
+        }
+
+        // The evacuated extents are free where they do not overlap the new metadata
+        let free_extents = free_extents
+            .into_iter()
+            .filter(|extent| extent.end > new_layout.data_start)
+            .map(|extent| ChunkOffset {
+                start: extent.start.max(new_layout.data_start),
+                end: extent.end,
+            })
+            .collect();
+
+        // Every chunk changes its home slot in the bigger offset map
+        let entries: Vec<ParseOffsetMapElem> = entries.into_iter().map(|(_, entry)| entry).collect();
+        let map_buf = encode_offset_map(&entries, new_slots)?;
+
+        self.transaction(file, |volume| {
+            volume.max_size = new_max_size;
+            volume.layout = new_layout;
+            volume.map_offsets_count = new_slots;
+            volume.map_offsets_tombstones = 0;
+            volume.free_extents = coalesce_extents(free_extents, new_max_size);
+
+            volume.write_meta(file, &map_buf, volume.layout.map_offsets_start)?;
+            volume.write_free_extents(file, 0, volume.free_extents.len())?;
+            return volume.write_header(file);
+        })?;
+
+        // The evacuated extents are free only once the new map reaches the volume file
+        if !self.pending.is_empty() {
+            self.flush(file)?;
+        }
+
+        return Ok(());
+    }
+
+    // Moves a chunk from the space taken by the bigger offset map to the data head
+    fn evacuate_chunk(&mut self, file: &File, new_layout: &VolumeLayout, slot: u64, entry: &mut ParseOffsetMapElem) -> Result<(), XEngineError> {
+        let target = ChunkOffset {
+            start: self.data_head,
+            end: self.data_head + entry.offset.len(),
+        };
+
+        if !new_layout.contains(&target) {
+            return Err(XEngineError::VolumeFull);
+        }
+
+        let buf = self.read_stored(file, &entry.offset)?;
+        self.write_data(file, buf, target.start)?;
+
+        self.data_head = target.end;
+        self.transaction(file, |volume| {
+            volume.write_offset_map_elem(file, slot, entry.uid.clone(), &target, MAP_OFFSETS_ELEM_LIVE, &entry.meta)?;
+            return volume.write_header(file);
+        })?;
+
+        if let Some(cached) = self.offsets.get_mut(&entry.uid) {
+            *cached = target;
+        }
+
+        entry.offset = target;
+
+        return Ok(());
+    }
+
+    fn shrink(&mut self, file: &File, new_max_size: u64) -> Result<(), XEngineError> {
+        let new_layout = VolumeLayout::from_max_size(new_max_size, self.chunk_size);
+        let new_slots = VolumeLayout::map_offsets_slots(new_max_size);
+        let old_data_start = self.layout.data_start;
+
+        let entries: Vec<(u64, ParseOffsetMapElem)> = self.read_offset_map_entries(file)?;
+
+        if entries.len() as u64 > new_max_size {
+            return Err(XEngineError::CannotShrinkVolume);
+        }
+
+        // The chunks past the new data region are moved into its free space, first fit,
+        // never over a chunk still to be moved
+        let (mut gaps, data_head) = extent_gaps(entries.iter().map(|(_, entry)| entry.offset).collect(), new_layout.data_start);
+        gaps.push(ChunkOffset {
+            start: data_head,
+            end: new_layout.data_end,
+        });
+
+        let (mut entries, moving): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|(_, entry)| new_layout.contains(&entry.offset));
+
+        let mut moves = Vec::new();
+
+        for (slot, entry) in moving {
+            let len = entry.offset.len();
+
+            let Some(gap) = gaps.iter_mut().find(|gap| gap.end.min(new_layout.data_end) >= gap.start + len) else {
+                return Err(XEngineError::CannotShrinkVolume);
+            };
+
+            let target = ChunkOffset {
+                start: gap.start,
+                end: gap.start + len,
+            };
+            gap.start = target.end;
+
+            moves.push((slot, entry, target));
+        }
+
+        // An interrupted shrink only leaks space, as a compaction
+        self.transaction(file, |volume| {
+            volume.free_extents.clear();
+            return volume.write_header(file);
+        })?;
+
+        let mut relocations = Vec::new();
+        let mut result = Ok(());
+
+        for (slot, entry, target) in moves.iter() {
+            // Targets over the old metadata are written with the smaller offset map,
+            // the other chunks are moved one at a time with the old layout
+            result = if target.start < old_data_start {
+                self.read_stored(file, &entry.offset)
+                    .map(|buf| relocations.push((entry.uid.clone(), *target, buf)))
+            } else {
+                self.move_chunk(file, *slot, entry, *target)
+            };
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        if let Err(err) = result {
+            self.rebuild_free_extents(file)?;
+            return Err(err);
+        }
+
+        for (slot, mut entry, target) in moves {
+            entry.offset = target;
+            entries.push((slot, entry));
+        }
+
+        let entries: Vec<ParseOffsetMapElem> = entries.into_iter().map(|(_, entry)| entry).collect();
+        let (free_extents, data_head) = extent_gaps(entries.iter().map(|entry| entry.offset).collect(), new_layout.data_start);
+
+        // The smaller offset map, the free extents and the header overlap the old
+        // regions, as may some moved chunks, they are replaced in a single transaction
+        let map_buf = encode_offset_map(&entries, new_slots)?;
+
+        self.transaction(file, |volume| {
+            volume.max_size = new_max_size;
+            volume.layout = new_layout;
+            volume.map_offsets_count = new_slots;
+            volume.map_offsets_tombstones = 0;
+            volume.free_extents = coalesce_extents(free_extents, new_max_size);
+            volume.data_head = data_head;
+
+            for (_, target, buf) in relocations.iter() {
+                volume.write_meta(file, buf, target.start)?;
+            }
+
+            volume.write_meta(file, &map_buf, volume.layout.map_offsets_start)?;
+            volume.write_free_extents(file, 0, volume.free_extents.len())?;
+            return volume.write_header(file);
+        })?;
+
+        for (uid, target, _) in relocations {
+            if let Some(cached) = self.offsets.get_mut(&uid) {
+                *cached = target;
+            }
+        }
+
+        // The header on disk must not point past the end of the file
+        if !self.pending.is_empty() {
+            self.flush(file)?;
+        }
+
+        if let Err(err) = file.set_len(new_layout.data_end) {
+            return Err(XEngineError::IO(err));
+        }
+
+        return Ok(());
+    }
+
+    fn read_stored(&self, file: &File, offset: &ChunkOffset) -> Result<Vec<u8>, XEngineError> {
+        let mut buf = vec![0u8; offset.len() as usize];
+
+        if let Err(err) = file.read_exact_at(&mut buf, offset.start) {
+            return Err(XEngineError::IO(err));
+        }
+
+        return Ok(buf);
+    }
+
+    // Copies a chunk into space no entry references and points its map entry to the copy
+    fn move_chunk(&mut self, file: &File, slot: u64, entry: &ParseOffsetMapElem, target: ChunkOffset) -> Result<(), XEngineError> {
+        let buf = self.read_stored(file, &entry.offset)?;
+        self.write_data(file, buf, target.start)?;
+
+        self.transaction(file, |volume| {
+            return volume.write_offset_map_elem(file, slot, entry.uid.clone(), &target, MAP_OFFSETS_ELEM_LIVE, &entry.meta);
+        })?;
+
+        if let Some(cached) = self.offsets.get_mut(&entry.uid) {
+            *cached = target;
+        }
+
+        if !self.pending.is_empty() {
+            self.flush(file)?;
+        }
+
+        return Ok(());
+    }
+
+    fn put_chunk(&mut self, file: &File, chunk: Chunk) -> Result<Option<String>, XEngineError> {
+        // A locked encrypted volume would otherwise take plaintext chunks
+        if self.key_slots.is_encrypted() && self.key.is_none() {
+            return Err(XEngineError::EncryptionKeyRequired);
+        }
+
+        // A plain content hash in the offset map would tell which known data the volume holds
+        if self.key.is_some() && chunk.uid == Chunk::build_content_uid(chunk.content()) {
+            return Err(XEngineError::UnkeyedContentUid(chunk.uid));
+        }
+
+        // Data regions are sized for chunk_size chunks, so a larger chunk could overflow a full volume
+        if chunk.data.len() as u64 > self.chunk_size {
+            return Err(XEngineError::InvalidChunkSize(chunk.data.len() as u64));
+        }
+
+        let max_size = self.get_max_size();
+        let actual_size = self.get_actual_size();
+
+        let chunk_uid = chunk.uid.clone();
+
+        // Incompressible chunks are stored raw, compression comes before encryption
+        let (codec, stored) = compress_chunk(self.codec, &chunk.data)?;
+        let (cipher, stored) = match self.key.as_ref() {
+            Some(key) => (ChunkCipher::ChaCha20Poly1305, encrypt_chunk(key, &chunk_uid, &seal_raw_len(&stored, chunk.data.len())?)?),
+            None => (ChunkCipher::None, stored),
+        };
+        let mut meta = ChunkMeta::from_stored(&stored, codec, cipher, &chunk.data);
+
+        let old_chunk = self.lookup_chunk(file, &chunk_uid)?;
+
+        // The same data under the same uid is stored once and only referenced again,
+        // however it was encoded when first stored. Chunks are not matched across uids,
+        // files share chunks through content addressing. Encrypted chunks have no raw
+        // checksum to filter on, their data is always compared
+        let encrypted = old_chunk.is_some_and(|(_, old_meta)| old_meta.cipher != ChunkCipher::None) || meta.cipher != ChunkCipher::None;
+
+        if let Some((old_offset, old_meta)) = old_chunk
+            && (encrypted || (old_meta.raw_checksum == meta.raw_checksum && old_meta.raw_len == meta.raw_len))
+            && self.stores_data(file, &chunk_uid, &old_offset, &old_meta, &chunk.data)?
+        {
+            return self.reference_chunk(file, chunk_uid, 1);
+        }
+
+        if old_chunk.is_none() && actual_size >= max_size {
+            return Err(XEngineError::VolumeFull);
+        }
+
+        // Replacing a chunk rewrites its own map entry and keeps its references,
+        // a new chunk takes a free map slot
+        let old_offset = old_chunk.map(|(offset, _)| offset);
+        if let Some((_, old_meta)) = old_chunk {
+            meta.refcount = old_meta.refcount;
+        }
+        let (slot, tombstone) = self.find_free_offset_map_slot(file, &chunk_uid)?;
+
+        let chunk_offset = self.alloc_extent(file, stored.len() as u64)?;
+
+        self.write_data(file, stored, chunk_offset.start)?;
+
+        // Chunk data is written in place, its map entry and the header go through the journal
+        self.write_offset_map_elem(file, slot, chunk_uid.clone(), &chunk_offset, MAP_OFFSETS_ELEM_LIVE, &meta)?;
+
+        if old_offset.is_none() {
+            self.actual_size += 1;
+        }
+        if tombstone {
+            self.map_offsets_tombstones -= 1;
+        }
+
+        if self.cached {
+            self.save_cached(&chunk_uid);
+            self.offsets.insert(chunk_uid.clone(), chunk_offset);
+            self.chunks_meta.insert(chunk_uid.clone(), meta);
+            self.chunks.insert(chunk_uid, chunk);
+        }
+
+        self.write_header(file)?;
+
+        if let Some(old_offset) = old_offset {
+            match self.batch.as_mut() {
+                Some(batch) => batch.released.push(old_offset),
+                None => self.release_extent(file, old_offset)?,
+            }
+        }
+        self.unsynced_chunks += 1;
+
+        return Ok(Some(self.uid.clone()));
+    }
+
+    // Adds delta to the refcount of a stored chunk, its data is left untouched
+    fn reference_chunk(&mut self, file: &File, uuid: String, delta: i64) -> Result<Option<String>, XEngineError> {
+        let Some((slot, entry)) = self.find_offset_map_elem(file, &uuid)? else {
+            return Ok(None);
+        };
+
+        let mut meta = entry.meta;
+        meta.refcount = meta.refcount.saturating_add_signed(delta);
+
+        self.write_offset_map_elem(file, slot, uuid.clone(), &entry.offset, MAP_OFFSETS_ELEM_LIVE, &meta)?;
+
+        self.save_cached(&uuid);
+        if let Some(cached) = self.chunks_meta.get_mut(&uuid) {
+            *cached = meta;
+        }
+        self.unsynced_chunks += 1;
+
+        return Ok(Some(self.uid.clone()));
+    }
+
+    // Decrypts and decompresses stored bytes already checked against the entry checksum
+    fn decode_chunk(&self, chunk_uid: &str, meta: &ChunkMeta, stored: &[u8]) -> Result<Vec<u8>, XEngineError> {
+        let decrypted;
+
+        let (stored, raw_len) = match meta.cipher {
+            ChunkCipher::None => (stored, meta.raw_len),
+            ChunkCipher::ChaCha20Poly1305 => {
+                let Some(key) = self.key.as_ref() else {
+                    return Err(XEngineError::EncryptionKeyRequired);
+                };
+
+                // Chunks not yet re-encrypted by a data key rotation use the previous key
+                decrypted = match (decrypt_chunk(key, chunk_uid, stored), self.previous_key.as_ref()) {
+                    (Err(XEngineError::DecryptionFailed(_)), Some(previous_key)) => decrypt_chunk(previous_key, chunk_uid, stored)?,
+                    (result, _) => result?,
+                };
+
+                if decrypted.len() < SEALED_RAW_LEN_LEN {
+                    return Err(XEngineError::DecryptionFailed(chunk_uid.to_string()));
+                }
+
+                let (raw_len_bytes, encoded) = decrypted.split_at(SEALED_RAW_LEN_LEN);
+                (encoded, decode_number(raw_len_bytes, &get_bincode_config())?)
+            }
+        };
+
+        return meta.codec.decompress(stored, raw_len as usize);
+    }
+
+    fn delete_chunk(&mut self, file: &File, uuid: String) -> Result<Option<String>, XEngineError> {
+        let Some((slot, entry)) = self.find_offset_map_elem(file, &uuid)? else {
+            return Ok(None);
+        };
+
+        // A shared chunk only loses a reference
+        if entry.meta.refcount > 1 {
+            return self.reference_chunk(file, uuid, -1);
+        }
+
+        let result = self.purge_chunk(file, slot, entry)?;
+        self.rehash_tombstones(file)?;
+
+        return Ok(result);
+    }
+
+    // Drops the map entry whatever its refcount
+    fn purge_chunk(&mut self, file: &File, slot: u64, entry: ParseOffsetMapElem) -> Result<Option<String>, XEngineError> {
+        let uuid = entry.uid;
+        let offset = entry.offset;
+
+        // Tombstone the map entry before the extent can be reused
+        self.write_offset_map_elem(file, slot, Uuid::nil().to_string(), &offset, MAP_OFFSETS_ELEM_TOMBSTONE, &ChunkMeta::default())?;
+        self.actual_size -= 1;
+        self.map_offsets_tombstones += 1;
+
+        self.save_cached(&uuid);
+        self.offsets.remove(&uuid);
+        self.chunks_meta.remove(&uuid);
+        self.chunks.remove(&uuid);
+
+        self.write_header(file)?;
+        self.release_extent(file, offset)?;
+        self.unsynced_chunks += 1;
+
+        return Ok(Some(self.uid.clone()));
+    }
+
+    fn rehash_tombstones(&mut self, file: &File) -> Result<(), XEngineError> {
+        // Relocations of a running compaction refer to the current slots
+        if !self.compacting && self.map_offsets_tombstones * MAP_OFFSETS_MAX_TOMBSTONES_DIVISOR > self.map_offsets_count {
+            return self.rehash_offset_map(file);
+        }
+
+        return Ok(());
+    }
+
+    // Rewrites the offset map without tombstones, so misses end at the first empty slot again
+    fn rehash_offset_map(&mut self, file: &File) -> Result<(), XEngineError> {
+        let entries: Vec<ParseOffsetMapElem> = self
+            .read_offset_map_entries(file)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+
+        let map_buf = encode_offset_map(&entries, self.map_offsets_count)?;
+
+        return self.transaction(file, |volume| {
+            volume.write_meta(file, &map_buf, volume.layout.map_offsets_start)?;
+            volume.map_offsets_tombstones = 0;
+
+            return volume.write_header(file);
+        });
+    }
+}
+
+fn map_offsets_home_slot(chunk_uid: &str, slots: u64) -> Result<u64, XEngineError> {
+    let Ok(uid) = Uuid::parse_str(chunk_uid) else {
+        return Err(XEngineError::InvalidUuid);
+    };
+
+    return Ok((uid.as_u128() % slots as u128) as u64);
+}
+
+fn seal_raw_len(encoded: &[u8], raw_len: usize) -> Result<Vec<u8>, XEngineError> {
+    let mut buf = encode_number(raw_len as u64, get_bincode_config())?;
+    buf.extend_from_slice(encoded);
+
+    return Ok(buf);
+}
+
+// Builds a whole offset map of the given slots, without tombstones
+fn encode_offset_map(entries: &[ParseOffsetMapElem], slots: u64) -> Result<Vec<u8>, XEngineError> {
+    let config = get_bincode_config();
+
+    let mut map_buf = vec![0u8; (slots * MAP_OFFSETS_ELEM_LEN) as usize];
+    let mut used = vec![false; slots as usize];
+
+    for entry in entries {
+        let home = map_offsets_home_slot(&entry.uid, slots)?;
+
+        let Some(slot) = (0..slots).map(|probe| (home + probe) % slots).find(|slot| !used[*slot as usize]) else {
+            return Err(XEngineError::VolumeFull);
+        };
+
+        let elem_bytes = encode_offset_map_elem(entry.uid.clone(), &entry.offset, MAP_OFFSETS_ELEM_LIVE, &entry.meta, config)?;
+        let elem_offset = (slot * MAP_OFFSETS_ELEM_LEN) as usize;
+
+        map_buf[elem_offset..elem_offset + elem_bytes.len()].copy_from_slice(&elem_bytes);
+        used[slot as usize] = true;
+    }
+
+    return Ok(map_buf);
+}
+
+// Free space between the given extents from data_start, and the end of the last one
+fn extent_gaps(mut extents: Vec<ChunkOffset>, data_start: u64) -> (Vec<ChunkOffset>, u64) {
+    extents.sort_by_key(|extent| extent.start);
+
+    let mut gaps = Vec::new();
+    let mut cursor = data_start;
+
+    for extent in extents {
+        if extent.start > cursor {
+            gaps.push(ChunkOffset {
+                start: cursor,
+                end: extent.start,
+            });
+        }
+        cursor = cursor.max(extent.end);
+    }
+
+    return (gaps, cursor);
+}
+
+fn coalesce_extents(extents: Vec<ChunkOffset>, max_len: u64) -> Vec<ChunkOffset> {
+    let mut extents: Vec<ChunkOffset> = extents.into_iter().filter(|x| !x.is_empty()).collect();
+    extents.sort_by_key(|x| x.start);
+
+    let mut coalesced: Vec<ChunkOffset> = Vec::with_capacity(extents.len());
+
+    for extent in extents {
+        match coalesced.last_mut() {
+            Some(last) if last.end >= extent.start => {
+                last.end = last.end.max(extent.end);
+            }
+            _ => coalesced.push(extent),
+        }
+    }
+
+    coalesced.truncate(max_len as usize);
+
+    return coalesced;
+}
+
+impl ChunksHandler for Volume {
+    fn get_max_size(&self) -> u64 {
+        return self.max_size;
+    }
+    
+    fn get_actual_size(&self) -> u64 {
+        return self.actual_size;
+    }
+
+    fn get_chunk(&mut self, uuid: String) -> Option<&Chunk> {
+        // TODO Lettura del chunk in base all'offset
+        return self.chunks.get(&uuid);
+    }
+
+    fn add_chunk(&mut self, chunk: Chunk) -> Option<String> {
+        let chunk_uid = chunk.uid.clone();
+        self.chunks.insert(chunk_uid, chunk);
+
+        return Some(self.uid.clone());
+    }
+
+    fn is_full(self) -> bool {
+        return self.chunks.len() >= self.max_size as usize;
+    }
+
+    fn get_chunk_v2(&mut self, file: &File, uuid: String) -> Result<Option<Chunk>, XEngineError> {
+        return self.read_chunk(file, uuid);
+    }
+
+    fn add_chunk_v2(&mut self, file: &File, chunk: Chunk) -> Result<Option<String>, XEngineError> {
+        return self.retry_full(file, |volume| volume.transaction(file, |volume| volume.put_chunk(file, chunk.clone())));
+    }
+
+    fn remove_chunk_v2(&mut self, file: &File, uuid: String) -> Result<Option<String>, XEngineError> {
+        return self.transaction(file, |volume| volume.delete_chunk(file, uuid));
+    }
+
+    // Chunks already stored only gain a reference, so capacity is checked per chunk.
+    // The whole batch is one transaction and its data is submitted with a single batched write
+    fn add_chunks_v2(&mut self, file: &File, chunks: &Vec<Chunk>) -> Result<(), XEngineError> {
+        return self.retry_full(file, |volume| volume.transaction(file, |volume| {
+            volume.batch = Some(DataBatch::default());
+
+            let result = chunks.iter().try_for_each(|chunk| volume.put_chunk(file, chunk.clone()).map(|_| ()));
+            let batch = volume.batch.take().unwrap_or_default();
+
+            result?;
+
+            let writes = coalesce_writes(batch.writes);
+
+            write_batch(volume.io_engine, file, &writes)?;
+            volume.data_dirty |= !writes.is_empty();
+
+            for extent in batch.released {
+                volume.release_extent(file, extent)?;
+            }
+
+            return Ok(());
+        }));
     }
 }
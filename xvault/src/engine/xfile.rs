@@ -22,16 +22,83 @@ use std::{
     os::unix::fs::MetadataExt,
     path::Path,
 };
+use fastcdc::v2020::{StreamCDC, AVERAGE_MIN, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN};
 use uuid::Uuid;
 
-use crate::engine::{chunk::{Chunk, CHUNK_SIZE}, error::XEngineError};
+use crate::engine::{chunk::{is_valid_chunk_size, Chunk, CHUNK_SIZE, MAX_CHUNK_SIZE}, error::XEngineError};
 
 pub type XFileChunks = Vec<Chunk>;
 
+/*
+    Fixed splits the file every chunk_size bytes, ContentDefined cuts it where
+    a FastCDC rolling hash matches, so an edit only changes the chunks around it
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum XFileChunking {
+    Fixed { chunk_size: usize },
+    ContentDefined { min_size: usize, avg_size: usize, max_size: usize },
+}
+
+impl XFileChunking {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            XFileChunking::Fixed { chunk_size } => {
+                return is_valid_chunk_size(chunk_size);
+            }
+            XFileChunking::ContentDefined { min_size, avg_size, max_size } => {
+                return min_size >= MINIMUM_MIN as usize
+                    && min_size <= MINIMUM_MAX as usize
+                    && avg_size >= AVERAGE_MIN as usize
+                    && max_size >= MAXIMUM_MIN as usize
+                    && max_size <= MAX_CHUNK_SIZE
+                    && min_size <= avg_size
+                    && avg_size <= max_size;
+            }
+        }
+    }
+
+    // Upper bound of a chunk data length
+    pub fn max_chunk_size(&self) -> usize {
+        match *self {
+            XFileChunking::Fixed { chunk_size } => return chunk_size,
+            XFileChunking::ContentDefined { max_size, .. } => return max_size,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct XFileQuery {
     pub uid: String,
     pub chunk_count: usize,
+    #[serde(default)]
+    pub chunk_uids: Vec<String>,
+}
+
+impl XFileQuery {
+    // Chunk uids are then built from the file uid and the chunk index
+    pub fn new(uid: String, chunk_count: usize) -> Self {
+        return XFileQuery {
+            uid: uid,
+            chunk_count: chunk_count,
+            chunk_uids: Vec::new(),
+        };
+    }
+}
+
+/*
+    Indexed chunk uids are UUIDv5 of the file uid and the chunk index,
+    content addressed ones are derived from the chunk data hash, keyed with the
+    data key once the file is addressed for an encrypted volume.
+
+    Volumes deduplicate chunks by uid, so identical chunks of different files are
+    stored once only when the files are content addressed. Indexed files only
+    share the chunks of the same file added again
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum XFileAddressing {
+    #[default]
+    Indexed,
+    ContentAddressed,
 }
 
 /*
@@ -42,6 +109,9 @@ pub struct XFile {
     pub uid: String,
     pub vpath: String,
     pub size: usize,
+    pub chunking: XFileChunking,
+    pub addressing: XFileAddressing,
+    pub chunk_uids: Vec<String>,
     pub chunks: XFileChunks,
 }
 
@@ -54,15 +124,74 @@ impl XFile {
     }
 
     pub fn get_chunk_uid(&self, index: usize) -> String {
+        if let Some(chunk_uid) = self.chunk_uids.get(index) {
+            return chunk_uid.clone();
+        }
         return XFile::build_chunk_uid(self.uid.clone(), index);
     }
 
+    pub fn chunk_size(&self) -> usize {
+        return self.chunking.max_chunk_size();
+    }
+
+    pub fn query(&self) -> XFileQuery {
+        return XFileQuery {
+            uid: self.uid.clone(),
+            chunk_count: self.chunk_uids.len(),
+            chunk_uids: self.chunk_uids.clone(),
+        };
+    }
+
+    // Content addressed chunks must hash to their own uid
+    pub fn verify_chunks(&self) -> bool {
+        return self.verify_chunks_with(Chunk::build_content_uid);
+    }
+
+    pub fn verify_chunks_with<F>(&self, build_uid: F) -> bool
+    where
+        F: Fn(&[u8]) -> String,
+    {
+        if self.addressing != XFileAddressing::ContentAddressed {
+            return true;
+        }
+        return self.chunks.iter().all(|chunk| chunk.uid == build_uid(chunk.content()));
+    }
+
+    // Derives the uids of content addressed chunks again, indexed chunks are left untouched
+    pub fn set_content_uids<F>(&mut self, build_uid: F)
+    where
+        F: Fn(&[u8]) -> String,
+    {
+        if self.addressing != XFileAddressing::ContentAddressed {
+            return;
+        }
+
+        for chunk in self.chunks.iter_mut() {
+            chunk.uid = build_uid(chunk.content());
+        }
+        self.chunk_uids = self.chunks.iter().map(|chunk| chunk.uid.clone()).collect();
+    }
+
     pub fn new(user_uid: Uuid, file_path: &Path, vfolder: String) -> Result<Self, io::Error> {
-        let file = fs::File::open(file_path);
+        return XFile::new_with_chunk_size(user_uid, file_path, vfolder, CHUNK_SIZE);
+    }
 
-        if let Ok(mut file) = file {
-            let mut chunks = Vec::new();
+    pub fn new_with_chunk_size(user_uid: Uuid, file_path: &Path, vfolder: String, chunk_size: usize) -> Result<Self, io::Error> {
+        return XFile::new_with_chunking(user_uid, file_path, vfolder, XFileChunking::Fixed { chunk_size });
+    }
+
+    pub fn new_with_chunking(user_uid: Uuid, file_path: &Path, vfolder: String, chunking: XFileChunking) -> Result<Self, io::Error> {
+        return XFile::new_with_addressing(user_uid, file_path, vfolder, chunking, XFileAddressing::Indexed);
+    }
+
+    pub fn new_with_addressing(user_uid: Uuid, file_path: &Path, vfolder: String, chunking: XFileChunking, addressing: XFileAddressing) -> Result<Self, io::Error> {
+        if !chunking.is_valid() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid XFile chunking parameters: {:?}", chunking)));
+        }
+
+        let file = fs::File::open(file_path);
 
+        if let Ok(file) = file {
             let filename = file_path.file_name().unwrap();
             let filename = filename.to_str().unwrap();
 
@@ -72,47 +201,91 @@ impl XFile {
             let metadata = file.metadata().unwrap();
             let file_length = metadata.size() as usize;
 
-            let mut buf = [0u8; CHUNK_SIZE];
-
-            let mut i: usize = 0;
-
-            loop {
-                let read_bytes = file.read(&mut buf).unwrap();
-                let data = buf.to_vec();
-
-                let chunk_uid = Uuid::new_v5(&file_uid, &i.to_be_bytes());
-                let mut length = None;
-
-                if read_bytes < CHUNK_SIZE {
-                    length = Some(file_length - (CHUNK_SIZE * i));
+            let mut chunks = match chunking {
+                XFileChunking::Fixed { chunk_size } => {
+                    XFile::read_fixed_chunks(file, file_uid, file_length, chunk_size)?
                 }
+                XFileChunking::ContentDefined { min_size, avg_size, max_size } => {
+                    XFile::read_content_defined_chunks(file, file_uid, min_size, avg_size, max_size)?
+                }
+            };
 
-                let chunk = Chunk {
-                    uid: chunk_uid.into(),
-                    data: data,
-                    length: length,
-                };
-
-                buf = [0u8; CHUNK_SIZE];
-                chunks.push(chunk);
-
-                if read_bytes < CHUNK_SIZE {
-                    break;
+            if addressing == XFileAddressing::ContentAddressed {
+                for chunk in chunks.iter_mut() {
+                    chunk.uid = Chunk::build_content_uid(chunk.content());
                 }
-                i += 1;
             }
 
+            let chunk_uids = chunks.iter().map(|chunk| chunk.uid.clone()).collect();
+
             return Ok(XFile {
                 uid: file_uid.into(),
                 vpath: vabs,
                 chunks: chunks,
                 size: file_length,
+                chunking: chunking,
+                addressing: addressing,
+                chunk_uids: chunk_uids,
             });
         } else {
             return Err(file.unwrap_err());
         }
     }
 
+    fn read_fixed_chunks(mut file: File, file_uid: Uuid, file_length: usize, chunk_size: usize) -> Result<XFileChunks, io::Error> {
+        let mut chunks = Vec::new();
+        let mut buf = vec![0u8; chunk_size];
+
+        let mut i: usize = 0;
+
+        loop {
+            let read_bytes = file.read(&mut buf)?;
+            let data = buf.to_vec();
+
+            let chunk_uid = Uuid::new_v5(&file_uid, &i.to_be_bytes());
+            let mut length = None;
+
+            if read_bytes < chunk_size {
+                length = Some(file_length - (chunk_size * i));
+            }
+
+            let chunk = Chunk {
+                uid: chunk_uid.into(),
+                data: data,
+                length: length,
+            };
+
+            buf = vec![0u8; chunk_size];
+            chunks.push(chunk);
+
+            if read_bytes < chunk_size {
+                break;
+            }
+            i += 1;
+        }
+
+        return Ok(chunks);
+    }
+
+    // Content defined chunks hold exactly their own bytes, so no length is needed
+    fn read_content_defined_chunks(file: File, file_uid: Uuid, min_size: usize, avg_size: usize, max_size: usize) -> Result<XFileChunks, io::Error> {
+        let mut chunks = Vec::new();
+        let chunker = StreamCDC::new(file, min_size as u32, avg_size as u32, max_size as u32);
+
+        for (i, result) in chunker.enumerate() {
+            let data = result?.data;
+            let chunk_uid = Uuid::new_v5(&file_uid, &i.to_be_bytes());
+
+            chunks.push(Chunk {
+                uid: chunk_uid.into(),
+                data: data,
+                length: None,
+            });
+        }
+
+        return Ok(chunks);
+    }
+
     pub fn export(self, path: String) -> Result<(), XEngineError> {
         let path = Path::new(&path);
 
@@ -43,7 +43,7 @@ fn main() {
         vol1.offsets.clear();
         vol1.chunks.clear();
 
-        vol1.read_headers(&mut fp, false).unwrap();
+        vol1.read_headers(&mut fp, true).unwrap();
 
 
         let chunk = vol1
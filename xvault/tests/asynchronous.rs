@@ -0,0 +1,89 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#![cfg(feature = "tokio")]
+
+use std::{fs, path::Path};
+use uuid::Uuid;
+use xvault::engine::{
+    asynchronous::{AsyncChunksHandler, AsyncVolume, AsyncXFileHandler},
+    error::XEngineError,
+    volume::Volume,
+};
+
+const USER_UID: &str = "da64d273-e31b-48ca-8184-c741a34cb92d";
+const DEVIDE_UID: &str = "4754f539-a953-4dc4-ad37-7a8ab142218c";
+
+const ASSETS_FOLDER: &str = "./assets";
+const EXPORTS_FOLDER: &str = "./exports/test_async";
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_volume_test_import_export() {
+    let vol_path = "./tmp/vol35025.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(32)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let export_path = Path::new(EXPORTS_FOLDER).join("alice29.txt");
+
+    let volume = AsyncVolume::open(volume, true).await.unwrap();
+    let file = volume.import_file(user_uid, file_path.clone(), "vfolder1".into()).await.unwrap();
+
+    assert_eq!(volume.get_actual_size(), file.chunks.len() as u64);
+
+    // Chunks are served concurrently from the blocking pool
+    let reads: Vec<_> = file
+        .chunks
+        .iter()
+        .map(|chunk| tokio::spawn(volume.get_chunk(chunk.uid.clone())))
+        .collect();
+
+    for (chunk, read) in file.chunks.iter().zip(reads) {
+        let stored = read.await.unwrap().unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    volume.read_headers(false).await.unwrap();
+    assert_eq!(volume.get_actual_size(), file.chunks.len() as u64);
+
+    let found = volume.find_file_chunks(file.query()).await.unwrap().unwrap();
+    assert_eq!(found.len(), file.chunks.len());
+
+    volume.export_file(file.clone(), export_path.clone()).await.unwrap();
+    assert_eq!(fs::read(&file_path).unwrap(), fs::read(&export_path).unwrap());
+
+    let removed = file.chunks[0].uid.clone();
+    volume.remove_chunk(removed.clone()).await.unwrap();
+    assert!(volume.get_chunk(removed.clone()).await.unwrap().is_none());
+
+    match volume.export_file(file, export_path).await {
+        Err(XEngineError::ChunkNotFound(chunk_uid)) => assert_eq!(chunk_uid, removed),
+        other => panic!("Expected ChunkNotFound, got {:?}", other),
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
@@ -29,10 +29,11 @@ use rand::{
 };
 use uuid::Uuid;
 use xvault::engine::{
-    chunk::{CHUNK_SIZE, ChunksHandler},
+    chunk::{Chunk, CHUNK_SIZE, ChunksHandler},
     device::Device,
+    error::XEngineError,
     volume::Volume,
-    xfile::{XFile, XFileHandler, XFileQuery},
+    xfile::{XFile, XFileAddressing, XFileChunking, XFileHandler, XFileQuery},
 };
 
 use crate::utils::compare_files;
@@ -138,10 +139,7 @@ fn test_file(file_path: &str) {
 
     //println!("Device: {:#?}", dev);
 
-    let query = XFileQuery {
-        uid: file.uid.clone(),
-        chunk_count: chunks_count,
-    };
+    let query = XFileQuery::new(file.uid.clone(), chunks_count);
 
     let find_chunks = dev.find_file_chunks(query);
     println!("Find chunks: {:#?}", find_chunks);
@@ -150,6 +148,9 @@ fn test_file(file_path: &str) {
         uid: file.uid.clone(),
         vpath: file.vpath,
         size: file.size,
+        chunking: file.chunking,
+        addressing: file.addressing,
+        chunk_uids: file.chunk_uids.clone(),
         chunks: find_chunks.unwrap_or_default(),
     };
 
@@ -157,4 +158,224 @@ fn test_file(file_path: &str) {
 
     compare_files(&assets_file_path, &export_file_path);
 }
+
+#[test]
+fn device_test_content_addressed_chunks() {
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let export_file_path = Path::new(EXPORTS_FOLDER).join("content_addressed/alice29.txt");
+
+    fs::remove_file(export_file_path.clone()).unwrap_or(());
+
+    let user_id = Uuid::parse_str(USER_UID).unwrap();
+    let chunking = XFileChunking::Fixed { chunk_size: CHUNK_SIZE };
+
+    let file1 = XFile::new_with_addressing(user_id, &assets_file_path, "home".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+    let file2 = XFile::new_with_addressing(user_id, &assets_file_path, "backup".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+
+    // Same content in two files has the same chunk uids
+    assert_ne!(file1.uid, file2.uid);
+    assert_eq!(file1.chunk_uids, file2.chunk_uids);
+    assert!(file1.verify_chunks());
+
+    let mut tampered = file1.clone();
+    tampered.chunks[0].data[0] ^= 0xFF;
+    assert!(!tampered.verify_chunks());
+
+    let mut vol = Volume::new();
+    vol.set_path(VOL_PATH_1.into())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(file1.chunks.len() as u64)
+        .build()
+        .unwrap();
+
+    for chunk in file1.chunks.iter().chain(file2.chunks.iter()) {
+        vol.add_chunk(chunk.clone());
+    }
+    assert_eq!(vol.chunks.len(), file1.chunks.len());
+
+    let mut dev = Device::new(DEVIDE_UID.into()).unwrap();
+    dev.add_volume(vol);
+
+    let find_chunks = dev.find_file_chunks(file2.query()).unwrap();
+    assert_eq!(find_chunks.len(), file2.chunks.len());
+
+    let mut new_file = file2.clone();
+    new_file.chunks = find_chunks;
+    new_file.export_path(&export_file_path).unwrap();
+
+    compare_files(&assets_file_path, &export_file_path);
+}
+
+#[test]
+fn device_test_dedup_refcount_v2() {
+    let vol_path = "./tmp/vol35046.rootfs";
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+
+    let user_id = Uuid::parse_str(USER_UID).unwrap();
+    let chunking = XFileChunking::Fixed { chunk_size: CHUNK_SIZE };
+
+    let file1 = XFile::new_with_addressing(user_id, &assets_file_path, "home".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+    let file2 = XFile::new_with_addressing(user_id, &assets_file_path, "backup".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+    let indexed1 = XFile::new_with_chunking(user_id, &assets_file_path, "home".into(), chunking).unwrap();
+    let indexed2 = XFile::new_with_chunking(user_id, &assets_file_path, "backup".into(), chunking).unwrap();
+    let chunks_count = file1.chunks.len() as u64;
+
+    let mut device = Device::new(DEVIDE_UID.into()).unwrap();
+    let mut volume = Volume::new();
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(device.uid.clone())
+        .set_max_size(3 * chunks_count)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    device.add_volume(volume);
+
+    // Content addressed files share their chunk uids, so the second one is stored by reference
+    device.add_chunks_v2(&fp, &file1.chunks).unwrap();
+    device.add_chunks_v2(&fp, &file2.chunks).unwrap();
+    assert_eq!(device.get_actual_size(), chunks_count);
+
+    // Indexed chunk uids differ between files, the same data is stored again
+    device.add_chunks_v2(&fp, &indexed1.chunks).unwrap();
+    device.add_chunks_v2(&fp, &indexed2.chunks).unwrap();
+    assert_eq!(device.get_actual_size(), 3 * chunks_count);
+    drop(fp);
+
+    // The references are counted in the offset map on disk
+    let (mut volume, fp) = Volume::open_existing_uncached(vol_path.to_string(), true).unwrap();
+
+    for chunk_uid in file1.chunk_uids.iter() {
+        assert_eq!(volume.get_chunk_refcount(&fp, chunk_uid).unwrap(), Some(2));
+    }
+
+    volume.remove_file_chunks(&fp, &file1).unwrap();
+    assert_eq!(volume.get_actual_size(), 3 * chunks_count);
+
+    for chunk in file2.chunks.iter() {
+        assert_eq!(volume.get_chunk_refcount(&fp, &chunk.uid).unwrap(), Some(1));
+        assert_eq!(volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+    }
+
+    // The last reference frees the chunks
+    volume.remove_file_chunks(&fp, &file2).unwrap();
+    volume.remove_file_chunks(&fp, &indexed1).unwrap();
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing_uncached(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.get_actual_size(), chunks_count);
+    assert!(!volume.free_extents.is_empty());
+
+    for chunk_uid in file2.chunk_uids.iter().chain(indexed1.chunk_uids.iter()) {
+        assert_eq!(volume.get_chunk_refcount(&fp, chunk_uid).unwrap(), None);
+    }
+
+    for chunk in indexed2.chunks.iter() {
+        assert_eq!(volume.get_chunk_refcount(&fp, &chunk.uid).unwrap(), Some(1));
+        assert_eq!(volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
 include!(concat!(env!("OUT_DIR"), "/generated_device_tests.rs"));
+
+#[test]
+fn device_test_chunks_v2() {
+    let vol_path = "./tmp/vol35030.rootfs";
+    let other_path = "./tmp/vol35031.rootfs";
+    let mut device = Device::new(DEVIDE_UID.into()).unwrap();
+
+    let mut volume = Volume::new();
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(device.uid.clone())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    let mut other = Volume::new();
+    other
+        .set_path(other_path.to_string())
+        .set_uid_from_device(device.uid.clone())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    fs::remove_file(other_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+    other.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    let other_fp = other.open(true).unwrap();
+    device.add_volume(volume);
+
+    let chunk = Chunk {
+        uid: XFile::build_chunk_uid(Uuid::new_v4().to_string(), 0),
+        data: vec![7u8; CHUNK_SIZE],
+        length: None,
+    };
+
+    // Calls are forwarded to the volume the file handle belongs to
+    assert!(device.add_chunk_v2(&fp, chunk.clone()).unwrap().is_some());
+    assert_eq!(device.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+
+    // A file handle of a volume not added to the device is rejected by every call
+    assert!(matches!(device.get_chunk_v2(&other_fp, chunk.uid.clone()), Err(XEngineError::VolumeNotFound)));
+    assert!(matches!(device.add_chunk_v2(&other_fp, chunk.clone()), Err(XEngineError::VolumeNotFound)));
+    assert!(matches!(device.remove_chunk_v2(&other_fp, chunk.uid.clone()), Err(XEngineError::VolumeNotFound)));
+    assert!(matches!(device.add_chunks_v2(&other_fp, &vec![chunk.clone()]), Err(XEngineError::VolumeNotFound)));
+
+    assert!(device.remove_chunk_v2(&fp, chunk.uid.clone()).unwrap().is_some());
+    assert!(device.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().is_none());
+    assert!(device.remove_chunk_v2(&fp, chunk.uid.clone()).unwrap().is_none());
+
+    fs::remove_file(vol_path).unwrap_or(());
+    fs::remove_file(other_path).unwrap_or(());
+}
+
+#[test]
+fn device_test_add_chunks_v2_full() {
+    let vol_path = "./tmp/vol35039.rootfs";
+    let mut device = Device::new(DEVIDE_UID.into()).unwrap();
+
+    let mut volume = Volume::new();
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(device.uid.clone())
+        .set_max_size(2)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    device.add_volume(volume);
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..3)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    device.add_chunks_v2(&fp, &chunks[..2].to_vec()).unwrap();
+
+    // Chunks already stored only gain a reference on a full device
+    device.add_chunks_v2(&fp, &chunks[..2].to_vec()).unwrap();
+    assert_eq!(device.get_actual_size(), 2);
+
+    let result = device.add_chunks_v2(&fp, &chunks[1..].to_vec());
+    assert!(matches!(result, Err(XEngineError::VolumeFull)));
+    assert!(device.get_chunk_v2(&fp, chunks[2].uid.clone()).unwrap().is_none());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
@@ -0,0 +1,255 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{fs, sync::{mpsc, Arc}, time::Duration};
+use uuid::Uuid;
+use xvault::engine::{
+    chunk::{Chunk, CHUNK_SIZE},
+    error::XEngineError,
+    journal::Durability,
+    queue::VolumeQueue,
+    shared::SharedVolume,
+    volume::Volume,
+    xfile::XFile,
+};
+
+const DEVIDE_UID: &str = "4754f539-a953-4dc4-ad37-7a8ab142218c";
+
+fn build_chunks(count: usize) -> Vec<Chunk> {
+    let file_uid = Uuid::new_v4().to_string();
+
+    return (0..count)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+}
+
+#[tokio::test]
+async fn volume_queue_test_batches_and_backpressure() {
+    let vol_path = "./tmp/vol35027.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(16)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    let queue = VolumeQueue::with_capacity(Arc::new(SharedVolume::new(volume, fp)), 4);
+    let chunks = build_chunks(17);
+
+    let tickets: Vec<_> = chunks[..8].iter().map(|chunk| queue.write(chunk.clone()).unwrap()).collect();
+    for ticket in tickets {
+        assert!(ticket.wait().unwrap().is_some());
+    }
+
+    // Callbacks complete on the worker thread
+    let (sender, receiver) = mpsc::channel();
+    for chunk in chunks[..8].iter() {
+        let sender = sender.clone();
+        queue.read_with(chunk.uid.clone(), move |result| sender.send(result.unwrap()).unwrap()).unwrap();
+    }
+    drop(sender);
+
+    let mut read: Vec<Chunk> = receiver.iter().map(|chunk| chunk.unwrap()).collect();
+    read.sort_by_key(|chunk| chunk.data[0]);
+    for (chunk, stored) in chunks[..8].iter().zip(read) {
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // Holding the volume stalls the worker, so the bounded queue fills up
+    let guard = queue.shared().write();
+    let mut accepted = Vec::new();
+    let mut full = false;
+
+    for chunk in chunks[8..].iter() {
+        match queue.try_write(chunk.clone()) {
+            Ok(ticket) => accepted.push(ticket),
+            Err(XEngineError::QueueFull) => full = true,
+            Err(err) => panic!("Unexpected error {:?}", err),
+        }
+    }
+    assert!(full);
+    drop(guard);
+
+    // Once full the queue refuses every request, so the accepted ones are a prefix
+    let stored = 8 + accepted.len();
+    for ticket in accepted {
+        ticket.await.unwrap();
+    }
+
+    // Only the chunks fitting in the volume are stored
+    let tickets: Vec<_> = chunks[stored..].iter().map(|chunk| queue.write(chunk.clone()).unwrap()).collect();
+    let mut results: Vec<_> = tickets.into_iter().map(|ticket| ticket.wait()).collect();
+    assert!(matches!(results.pop(), Some(Err(XEngineError::VolumeFull))));
+    assert!(results.iter().all(|result| result.is_ok()));
+
+    assert!(queue.read(Uuid::new_v4().to_string()).unwrap().await.unwrap().is_none());
+    assert_eq!(queue.read(chunks[15].uid.clone()).unwrap().await.unwrap().unwrap().data, chunks[15].data);
+
+    let shared = Arc::try_unwrap(queue.shutdown()).unwrap();
+    assert_eq!(shared.get_actual_size(), 16);
+
+    let (_, fp) = shared.into_inner();
+    drop(fp);
+
+    let (volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    for chunk in chunks[..16].iter() {
+        assert_eq!(volume.read_chunk(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_queue_test_group_commit_on_idle() {
+    let vol_path = "./tmp/vol35029.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(8)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+    volume.set_durability(Durability::GroupCommit { interval_ms: 20, chunks: 1000 });
+
+    let fp = volume.open(true).unwrap();
+    let queue = VolumeQueue::new(Arc::new(SharedVolume::new(volume, fp)));
+    let chunks = build_chunks(4);
+
+    // The group never reaches its chunk count, the idle worker syncs it before the write completes
+    for chunk in chunks.iter() {
+        queue.write(chunk.clone()).unwrap().wait().unwrap();
+        assert!(!queue.shared().read().has_unsynced());
+    }
+
+    let shared = Arc::try_unwrap(queue.shutdown()).unwrap();
+    assert_eq!(shared.get_actual_size(), 4);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_queue_test_group_commit_completion() {
+    let vol_path = "./tmp/vol35037.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(8)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+    volume.set_durability(Durability::GroupCommit { interval_ms: 60_000, chunks: 2 });
+
+    let fp = volume.open(true).unwrap();
+    let queue = VolumeQueue::new(Arc::new(SharedVolume::new(volume, fp)));
+    let chunks = build_chunks(3);
+
+    // A stored write waits for the group to reach its chunk count
+    let first = queue.write(chunks[0].clone()).unwrap();
+    queue.read(chunks[0].uid.clone()).unwrap().wait().unwrap().unwrap();
+    assert!(!first.is_done());
+    assert!(queue.shared().read().has_unsynced());
+
+    queue.write(chunks[1].clone()).unwrap().wait().unwrap();
+    assert!(first.is_done());
+    first.wait().unwrap();
+    assert!(!queue.shared().read().has_unsynced());
+
+    // Stopping the queue syncs the open group and completes its writes
+    let last = queue.write(chunks[2].clone()).unwrap();
+    let shared = Arc::try_unwrap(queue.shutdown()).unwrap();
+    last.wait().unwrap();
+    assert!(!shared.read().has_unsynced());
+    assert_eq!(shared.get_actual_size(), 3);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_queue_test_order_and_callbacks() {
+    let vol_path = "./tmp/vol35036.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(8)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    let queue = VolumeQueue::new(Arc::new(SharedVolume::new(volume, fp)));
+    let chunks = build_chunks(2);
+
+    // A callback may use the volume, it blocks the worker until the next requests are queued
+    let (gate_sender, gate) = mpsc::channel::<()>();
+    let (sender, receiver) = mpsc::channel();
+    let shared = queue.shared().clone();
+    let uid = chunks[0].uid.clone();
+
+    queue
+        .write_with(chunks[0].clone(), move |result| {
+            result.unwrap();
+            gate.recv().unwrap();
+            sender.send(shared.get_chunk(uid).unwrap().unwrap()).unwrap();
+        })
+        .unwrap();
+
+    let changed = Chunk {
+        uid: chunks[0].uid.clone(),
+        data: vec![9u8; CHUNK_SIZE],
+        length: None,
+    };
+
+    // Taken as one batch, each read sees only the writes submitted before it
+    let before = queue.read(chunks[0].uid.clone()).unwrap();
+    let write = queue.write(changed.clone()).unwrap();
+    let after = queue.read(chunks[0].uid.clone()).unwrap();
+    gate_sender.send(()).unwrap();
+
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(10)).unwrap().data, chunks[0].data);
+    assert_eq!(before.wait().unwrap().unwrap().data, chunks[0].data);
+    write.wait().unwrap();
+    assert_eq!(after.wait().unwrap().unwrap().data, changed.data);
+
+    let shared = Arc::try_unwrap(queue.shutdown()).unwrap();
+    assert_eq!(shared.get_actual_size(), 1);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
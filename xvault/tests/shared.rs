@@ -0,0 +1,166 @@
+/*
+Copyright (C) 2025 Antonio Ricciardi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{collections::HashSet, fs, sync::atomic::{AtomicBool, Ordering}, thread};
+use uuid::Uuid;
+use xvault::engine::{chunk::{Chunk, CHUNK_SIZE}, shared::SharedVolume, volume::Volume, xfile::XFile};
+
+const DEVIDE_UID: &str = "4754f539-a953-4dc4-ad37-7a8ab142218c";
+
+const READERS: usize = 8;
+
+#[test]
+fn shared_volume_test_concurrent_readers_and_writer() {
+    let vol_path = "./tmp/vol35024.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(64)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..64)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    let shared = SharedVolume::new(volume, fp);
+    shared.add_chunks(&chunks[..32].to_vec()).unwrap();
+
+    // Readers go through &self while a single writer keeps adding chunks
+    thread::scope(|scope| {
+        for reader in 0..READERS {
+            let shared = &shared;
+            let chunks = &chunks;
+
+            scope.spawn(move || {
+                for round in 0..8 {
+                    for chunk in chunks[..32].iter().skip((reader + round) % 4) {
+                        let stored = shared.get_chunk(chunk.uid.clone()).unwrap().unwrap();
+                        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+                    }
+                }
+            });
+        }
+
+        scope.spawn(|| {
+            for chunk in chunks[32..].iter() {
+                shared.add_chunk(chunk.clone()).unwrap();
+            }
+        });
+    });
+
+    assert_eq!(shared.get_actual_size(), 64);
+
+    for chunk in chunks.iter() {
+        let stored = shared.get_chunk(chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    shared.remove_chunk(chunks[0].uid.clone()).unwrap();
+    assert!(shared.get_chunk(chunks[0].uid.clone()).unwrap().is_none());
+    assert!(shared.read().scrub(shared.file()).unwrap().is_clean());
+
+    // The file handle and its lock are released with the shared volume
+    let (_, fp) = shared.into_inner();
+    drop(fp);
+
+    let shared = SharedVolume::open_existing_uncached(vol_path.to_string(), false).unwrap();
+    assert_eq!(shared.get_actual_size(), 63);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn shared_volume_test_compaction_with_readers() {
+    let vol_path = "./tmp/vol35032.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(48)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..40)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    let shared = SharedVolume::new(volume, fp);
+    shared.add_chunks(&chunks[..32].to_vec()).unwrap();
+
+    // Every other chunk is dead, so almost every live one is relocated
+    let live: Vec<&Chunk> = chunks[..32].iter().step_by(2).collect();
+    let live_chunks: HashSet<String> = live.iter().map(|chunk| chunk.uid.clone()).collect();
+    let compacting = AtomicBool::new(true);
+
+    let reclaimed = thread::scope(|scope| {
+        for _ in 0..READERS {
+            scope.spawn(|| {
+                while compacting.load(Ordering::Relaxed) {
+                    for chunk in live.iter() {
+                        let stored = shared.get_chunk(chunk.uid.clone()).unwrap().unwrap();
+                        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+                    }
+                }
+            });
+        }
+
+        // New chunks are appended while the compaction runs
+        scope.spawn(|| {
+            for chunk in chunks[32..].iter() {
+                shared.add_chunk(chunk.clone()).unwrap();
+            }
+        });
+
+        let reclaimed = shared.compact(&live_chunks).unwrap();
+        compacting.store(false, Ordering::Relaxed);
+
+        return reclaimed;
+    });
+
+    assert!(reclaimed > 0 || !shared.read().free_extents.is_empty());
+    assert_eq!(shared.get_actual_size(), 24);
+    assert!(shared.read().scrub(shared.file()).unwrap().is_clean());
+
+    for chunk in live.iter().copied().chain(chunks[32..].iter()) {
+        let stored = shared.get_chunk(chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
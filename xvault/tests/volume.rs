@@ -15,9 +15,14 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{fs::{self, OpenOptions}, path::Path};
+use std::{collections::{HashMap, HashSet}, fs::{self, File, OpenOptions}, os::unix::fs::FileExt, path::Path, sync::Arc};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+use xvault::engine::compression::ChunkCodec;
+#[cfg(feature = "encryption")]
+use xvault::engine::crypto::ChunkKey;
 use uuid::Uuid;
-use xvault::engine::{chunk::ChunksHandler, volume::Volume, xfile::XFile};
+use xvault::engine::{chunk::{Chunk, ChunksHandler, CHUNK_SIZE}, error::XEngineError, io_engine::IoEngine, journal::{append_journal, journal_path, write_journal, Durability, JournalRecord}, utils::compute_checksum, queue::VolumeQueue, shared::SharedVolume, volume::{ChunkOffset, Volume, MAP_OFFSETS_ELEM_LEN}, xfile::{XFile, XFileAddressing, XFileChunking}};
 const USER_UID: &str = "da64d273-e31b-48ca-8184-c741a34cb92d";
 const DEVIDE_UID: &str = "4754f539-a953-4dc4-ad37-7a8ab142218c";
 const ASSETS_FOLDER: &str = "./assets";
@@ -72,6 +77,2016 @@ fn volume_test_set_uid_from_disk() {
     assert_eq!(volume.uid, volume_uid);
 }
 
+#[test]
+fn volume_test_offsets_persisted_on_add_chunk() {
+    let vol_path = "./tmp/vol35004.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(10)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..4)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![i as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+    // Replacing a chunk must not grow the offset map
+    volume.add_chunk_v2(&fp, chunks[1].clone()).unwrap();
+    drop(fp);
+
+    // No write_headers: every add_chunk_v2 is already on disk
+    let mut reopened = Volume::new();
+    let mut fp = reopened.set_path(vol_path.to_string()).open(false).unwrap();
+    reopened.read_headers(&mut fp, true).unwrap();
+
+    assert_eq!(reopened.uid, volume.uid);
+    assert_eq!(reopened.offsets.len(), chunks.len());
+
+    for (uid, offset) in volume.offsets.iter() {
+        let reopened_offset = reopened.offsets[uid];
+        assert_eq!(reopened_offset.start, offset.start, "Different start index value for chunk: {}", uid);
+        assert_eq!(reopened_offset.end, offset.end, "Different end index value for chunk: {}", uid);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_chunks_data_region() {
+    let vol_path = "./tmp/vol35005.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..4)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let mut fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+    for offset in volume.offsets.values() {
+        assert!(offset.start >= volume.layout.data_start);
+        assert!(offset.end <= volume.layout.data_end);
+    }
+
+    // Rewriting the whole offset map must not touch the chunks data
+    volume.write_headers(&mut fp).unwrap();
+    volume.read_headers(&mut fp, true).unwrap();
+
+    for chunk in chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data overwritten", chunk.uid);
+    }
+
+    // A chunk bigger than the chunk size never reaches the data region
+    volume.remove_chunk_v2(&fp, chunks[0].uid.clone()).unwrap();
+    let oversized = Chunk {
+        uid: chunks[0].uid.clone(),
+        data: vec![0u8; CHUNK_SIZE * 2],
+        length: None,
+    };
+    let result = volume.add_chunk_v2(&fp, oversized);
+    assert!(matches!(result, Err(XEngineError::InvalidChunkSize(len)) if len == 2 * CHUNK_SIZE as u64));
+
+    // The data region is exhausted once it holds max_size chunks
+    volume.add_chunk_v2(&fp, chunks[0].clone()).unwrap();
+    let extra = Chunk {
+        uid: XFile::build_chunk_uid(file_uid.clone(), 4),
+        data: vec![5u8; CHUNK_SIZE],
+        length: None,
+    };
+    let result = volume.add_chunk_v2(&fp, extra);
+    assert!(matches!(result, Err(XEngineError::VolumeFull)));
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_superblock_validation() {
+    let vol_path = "./tmp/vol35006.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let mut fp = volume.open(true).unwrap();
+    assert!(volume.read_headers(&mut fp, false).is_ok());
+
+    // Flip one bit of the volume uid
+    let mut buf = [0u8; 1];
+    fp.read_exact_at(&mut buf, 24).unwrap();
+    fp.write_all_at(&[buf[0] ^ 0x01], 24).unwrap();
+
+    let result = volume.read_headers(&mut fp, false);
+    assert!(matches!(result, Err(XEngineError::CorruptHeader)));
+
+    // Unknown format version
+    let mut version = [0u8; 8];
+    version[0] = 0xff;
+    fp.write_all_at(&version, 8).unwrap();
+
+    let result = volume.read_headers(&mut fp, false);
+    assert!(matches!(result, Err(XEngineError::UnsupportedVersion(255))));
+
+    // Not a volume at all
+    fp.write_all_at(b"NOTAVOLM", 0).unwrap();
+
+    let result = volume.read_headers(&mut fp, false);
+    assert!(matches!(result, Err(XEngineError::NotAVolume)));
+
+    fp.set_len(16).unwrap();
+
+    let result = volume.read_headers(&mut fp, false);
+    assert!(matches!(result, Err(XEngineError::NotAVolume)));
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_open_existing() {
+    let vol_path = "./tmp/vol35007.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(8)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..6)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks[..3].to_vec()).unwrap();
+    drop(fp);
+
+    let (mut reopened, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+
+    assert_eq!(reopened.uid, volume.uid);
+    assert_eq!(Path::new(&reopened.path), fs::canonicalize(vol_path).unwrap());
+    assert_eq!(reopened.max_size, volume.max_size);
+    assert_eq!(reopened.layout, volume.layout);
+    assert_eq!(reopened.get_actual_size(), 3);
+
+    // The reopened volume keeps accepting chunks after the existing ones
+    reopened.add_chunks_v2(&fp, &chunks[3..].to_vec()).unwrap();
+    drop(fp);
+
+    let (mut reopened, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    assert_eq!(reopened.get_actual_size(), chunks.len() as u64);
+
+    for chunk in chunks.iter() {
+        let stored = reopened.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+
+    let result = Volume::open_existing(vol_path.to_string(), false);
+    assert!(matches!(result, Err(XEngineError::FileNotExists)));
+}
+
+#[test]
+fn volume_test_remove_chunk_reuses_extent() {
+    let vol_path = "./tmp/vol35008.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..5)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks[..4].to_vec()).unwrap();
+
+    let removed_offset = volume.offsets[&chunks[1].uid];
+    let removed = volume.remove_chunk_v2(&fp, chunks[1].uid.clone()).unwrap();
+    assert_eq!(removed, Some(volume.uid.clone()));
+    assert_eq!(volume.get_actual_size(), 3);
+    assert!(volume.get_chunk_v2(&fp, chunks[1].uid.clone()).unwrap().is_none());
+
+    let missing = volume.remove_chunk_v2(&fp, chunks[1].uid.clone()).unwrap();
+    assert!(missing.is_none());
+
+    // The tombstone and the freed extent survive a reopen
+    drop(fp);
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.get_actual_size(), 3);
+    assert!(!volume.offsets.contains_key(&chunks[1].uid));
+    assert_eq!(volume.free_extents.len(), 1);
+
+    // A full volume takes the new chunk in the freed extent
+    volume.add_chunk_v2(&fp, chunks[4].clone()).unwrap();
+
+    let offset = volume.offsets[&chunks[4].uid];
+    assert_eq!(offset.start, removed_offset.start);
+    assert_eq!(offset.end, removed_offset.end);
+    assert!(volume.free_extents.is_empty());
+
+    drop(fp);
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+
+    for chunk in [&chunks[0], &chunks[2], &chunks[3], &chunks[4]] {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_remove_chunk_coalesces_extents() {
+    let vol_path = "./tmp/vol35009.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..4)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+    volume.remove_chunk_v2(&fp, chunks[0].uid.clone()).unwrap();
+    volume.remove_chunk_v2(&fp, chunks[2].uid.clone()).unwrap();
+    assert_eq!(volume.free_extents.len(), 2);
+
+    volume.remove_chunk_v2(&fp, chunks[1].uid.clone()).unwrap();
+    assert_eq!(volume.free_extents.len(), 1);
+    assert_eq!(volume.free_extents[0].len(), 3 * CHUNK_SIZE as u64);
+
+    // A new chunk takes the start of the coalesced extent
+    let chunk = Chunk {
+        uid: XFile::build_chunk_uid(file_uid.clone(), 4),
+        data: vec![9u8; CHUNK_SIZE],
+        length: None,
+    };
+    volume.add_chunk_v2(&fp, chunk.clone()).unwrap();
+    assert_eq!(volume.free_extents.len(), 1);
+    assert_eq!(volume.free_extents[0].len(), 2 * CHUNK_SIZE as u64);
+
+    let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+    assert_eq!(stored.data, chunk.data);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_fragmented_volume() {
+    let vol_path = "./tmp/vol35044.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..6)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; if i < 4 { 3000 } else { CHUNK_SIZE }],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks[..4].to_vec()).unwrap();
+
+    volume.remove_chunk_v2(&fp, chunks[0].uid.clone()).unwrap();
+    volume.remove_chunk_v2(&fp, chunks[2].uid.clone()).unwrap();
+    volume.add_chunk_v2(&fp, chunks[4].clone()).unwrap();
+
+    // The free space left is enough for a chunk, but split among extents too short for it
+    let result = volume.add_chunk_v2(&fp, chunks[5].clone());
+    assert!(matches!(result, Err(XEngineError::VolumeFragmented)));
+    assert_eq!(volume.get_actual_size(), 3);
+
+    let live_chunks: HashSet<String> = [1, 3, 4].iter().map(|i| chunks[*i].uid.clone()).collect();
+    volume.compact(&fp, &live_chunks).unwrap();
+    volume.add_chunk_v2(&fp, chunks[5].clone()).unwrap();
+
+    for i in [1, 3, 4, 5] {
+        let stored = volume.get_chunk_v2(&fp, chunks[i].uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunks[i].data, "Chunk {} data mismatch", chunks[i].uid);
+    }
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_compact() {
+    let vol_path = "./tmp/vol35010.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(6)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..6)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+    volume.remove_chunk_v2(&fp, chunks[1].uid.clone()).unwrap();
+    volume.add_chunk_v2(&fp, chunks[3].clone()).unwrap();
+
+    // Chunk 3 is no longer referenced by the application, whatever its refcount
+    let live_chunks: HashSet<String> = [0, 2, 4, 5].iter().map(|i| chunks[*i].uid.clone()).collect();
+
+    let reclaimed = volume.compact(&fp, &live_chunks).unwrap();
+    assert_eq!(reclaimed, 2 * CHUNK_SIZE as u64);
+    assert_eq!(volume.get_actual_size(), 4);
+    assert!(volume.free_extents.is_empty());
+    assert_eq!(volume.get_data_head(), volume.layout.data_start + 4 * CHUNK_SIZE as u64);
+
+    drop(fp);
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.get_actual_size(), 4);
+
+    for i in [0, 2, 4, 5] {
+        let chunk = &chunks[i];
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    assert!(volume.get_chunk_v2(&fp, chunks[3].uid.clone()).unwrap().is_none());
+
+    // Nothing left to reclaim
+    let reclaimed = volume.compact(&fp, &live_chunks).unwrap();
+    assert_eq!(reclaimed, 0);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_resize() {
+    let vol_path = "./tmp/vol35011.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..40)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks[..4].to_vec()).unwrap();
+    volume.remove_chunk_v2(&fp, chunks[3].uid.clone()).unwrap();
+
+    // The bigger offset map takes the space of the first chunks
+    let old_data_start = volume.layout.data_start;
+
+    volume.resize(&fp, 40).unwrap();
+    assert_eq!(volume.max_size, 40);
+    assert!(volume.layout.data_start > old_data_start);
+    assert_eq!(volume.layout.data_start % CHUNK_SIZE as u64, 0);
+    assert_eq!(fp.metadata().unwrap().len(), volume.layout.data_end);
+
+    volume.add_chunks_v2(&fp, &chunks[4..8].to_vec()).unwrap();
+    assert_eq!(volume.get_actual_size(), 7);
+
+    drop(fp);
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.max_size, 40);
+
+    for i in [0, 1, 2, 4, 5, 6, 7] {
+        let chunk = &chunks[i];
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // Every chunk of the grown volume is usable
+    volume.add_chunk_v2(&fp, chunks[3].clone()).unwrap();
+    volume.add_chunks_v2(&fp, &chunks[8..].to_vec()).unwrap();
+    assert_eq!(volume.get_actual_size(), 40);
+
+    // Too many chunks for the new size
+    let result = volume.resize(&fp, 4);
+    assert!(matches!(result, Err(XEngineError::CannotShrinkVolume)));
+
+    for chunk in &chunks[3..] {
+        volume.remove_chunk_v2(&fp, chunk.uid.clone()).unwrap();
+    }
+
+    // The chunks left past the smaller data region are moved into it
+    volume.resize(&fp, 4).unwrap();
+    assert_eq!(volume.max_size, 4);
+    assert_eq!(fp.metadata().unwrap().len(), volume.layout.data_end);
+
+    drop(fp);
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.max_size, 4);
+    assert_eq!(volume.get_actual_size(), 3);
+
+    for i in [0, 1, 2] {
+        let chunk = &chunks[i];
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    volume.add_chunk_v2(&fp, chunks[3].clone()).unwrap();
+    assert_eq!(volume.get_actual_size(), 4);
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_shrink_moves_chunks() {
+    let vol_path = "./tmp/vol35035.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(64)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let chunk = Chunk {
+        uid: Uuid::new_v4().to_string(),
+        data: vec![7u8; CHUNK_SIZE],
+        length: None,
+    };
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunk_v2(&fp, chunk.clone()).unwrap();
+
+    // The chunk lies where the smaller volume ends, it is moved over the old metadata
+    volume.resize(&fp, 2).unwrap();
+    assert_eq!(volume.max_size, 2);
+    assert!(volume.layout.contains(&volume.offsets[&chunk.uid]));
+    assert_eq!(fp.metadata().unwrap().len(), volume.layout.data_end);
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.get_actual_size(), 1);
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+    assert_eq!(stored.data, chunk.data);
+
+    // The second chunk fits in the space left
+    let other = Chunk {
+        uid: Uuid::new_v4().to_string(),
+        data: vec![8u8; CHUNK_SIZE],
+        length: None,
+    };
+    volume.add_chunk_v2(&fp, other.clone()).unwrap();
+    assert_eq!(volume.get_chunk_v2(&fp, other.uid.clone()).unwrap().unwrap().data, other.data);
+    drop(fp);
+
+    // Chunks moved past the old metadata are copied one at a time, the others over it
+    fs::remove_file(vol_path).unwrap_or(());
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(64)
+        .build()
+        .unwrap();
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..12)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+    for chunk in chunks[..6].iter() {
+        volume.remove_chunk_v2(&fp, chunk.uid.clone()).unwrap();
+    }
+
+    let old_data_start = volume.layout.data_start;
+    volume.resize(&fp, 6).unwrap();
+    let moved: Vec<ChunkOffset> = chunks[6..].iter().map(|chunk| volume.offsets[&chunk.uid]).collect();
+    assert!(moved.iter().all(|offset| volume.layout.contains(offset)));
+    assert!(moved.iter().any(|offset| offset.start < old_data_start));
+    assert!(moved.iter().any(|offset| offset.start >= old_data_start));
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.get_actual_size(), 6);
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    for chunk in chunks[6..].iter() {
+        assert_eq!(volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_chunk_checksum() {
+    let vol_path = "./tmp/vol35012.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..2)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+    // Flip a byte of the first chunk behind the volume back
+    let offset = volume.offsets[&chunks[0].uid];
+    fp.write_all_at(&[0xFF], offset.start + 10).unwrap();
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+
+    let result = volume.get_chunk_v2(&fp, chunks[0].uid.clone());
+    match result {
+        Err(XEngineError::ChecksumMismatch { chunk_uid, volume_uid }) => {
+            assert_eq!(chunk_uid, chunks[0].uid);
+            assert_eq!(volume_uid, volume.uid);
+        }
+        other => panic!("Expected ChecksumMismatch, got {:?}", other),
+    }
+
+    let stored = volume.get_chunk_v2(&fp, chunks[1].uid.clone()).unwrap().unwrap();
+    assert_eq!(stored.data, chunks[1].data);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_scrub() {
+    let vol_path = "./tmp/vol35013.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..4)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+    let report = volume.scrub(&fp).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.ok.len(), 4);
+
+    // Map entries start with the chunk uid, followed by the chunk start and end
+    let map_len = (volume.map_offsets_count * MAP_OFFSETS_ELEM_LEN) as usize;
+    let map_start = volume.layout.map_offsets_start as usize;
+    let map = fs::read(vol_path).unwrap()[map_start..map_start + map_len].to_vec();
+
+    let entry_offset = |index: usize| {
+        let uid_bytes = Uuid::parse_str(&chunks[index].uid).unwrap().to_bytes_le();
+        let slot = map
+            .chunks_exact(MAP_OFFSETS_ELEM_LEN as usize)
+            .position(|entry| entry[..16] == uid_bytes)
+            .unwrap();
+
+        return volume.layout.map_offsets_start + slot as u64 * MAP_OFFSETS_ELEM_LEN;
+    };
+    let chunk0 = volume.offsets[&chunks[0].uid];
+
+    // Chunk 0 rots, chunk 1 points past the data region, chunk 2 overlaps chunk 0
+    fp.write_all_at(&[0xFF], chunk0.start).unwrap();
+
+    let data_end = volume.layout.data_end;
+    fp.write_all_at(&data_end.to_le_bytes(), entry_offset(1) + 16).unwrap();
+    fp.write_all_at(&(data_end + CHUNK_SIZE as u64).to_le_bytes(), entry_offset(1) + 24).unwrap();
+
+    fp.write_all_at(&(chunk0.start + 100).to_le_bytes(), entry_offset(2) + 16).unwrap();
+    fp.write_all_at(&(chunk0.end + 100).to_le_bytes(), entry_offset(2) + 24).unwrap();
+
+    let report = volume.scrub(&fp).unwrap();
+    assert!(!report.is_clean());
+
+    assert_eq!(report.ok, vec![chunks[3].uid.clone()]);
+    assert!(report.corrupt.contains(&chunks[0].uid));
+    assert!(report.corrupt.contains(&chunks[2].uid));
+    assert_eq!(report.out_of_bounds, vec![chunks[1].uid.clone()]);
+    assert_eq!(report.overlapping, vec![(chunks[0].uid.clone(), chunks[2].uid.clone())]);
+    assert!(report.overlapping_free.is_empty());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_journal_replay() {
+    let vol_path = "./tmp/vol35014.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..2)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    let data_start = volume.layout.data_start as usize;
+    let journal = journal_path(&volume.path);
+
+    volume.add_chunk_v2(&fp, chunks[0].clone()).unwrap();
+    let metadata_before = fs::read(vol_path).unwrap()[..data_start].to_vec();
+
+    volume.add_chunk_v2(&fp, chunks[1].clone()).unwrap();
+    let metadata_after = fs::read(vol_path).unwrap()[..data_start].to_vec();
+    assert!(!fs::exists(&journal).unwrap());
+
+    // Crash after the journal was synced but before the metadata reached the volume
+    fp.write_all_at(&metadata_before, 0).unwrap();
+    let records = vec![JournalRecord {
+        offset: 0,
+        data: metadata_after.clone(),
+    }];
+    write_journal(&journal, &records).unwrap();
+    drop(fp);
+
+    // Only the writer replays the journal
+    let result = Volume::open_existing(vol_path.to_string(), false);
+    assert!(matches!(result, Err(XEngineError::JournalPending)));
+    assert!(fs::exists(&journal).unwrap());
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert!(!fs::exists(&journal).unwrap());
+    assert_eq!(volume.get_actual_size(), 2);
+
+    for chunk in chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data);
+    }
+    drop(fp);
+
+    // A torn journal was never applied and is discarded
+    let fp = OpenOptions::new().write(true).open(vol_path).unwrap();
+    fp.write_all_at(&metadata_before, 0).unwrap();
+    write_journal(&journal, &records).unwrap();
+
+    let journal_len = fs::metadata(&journal).unwrap().len();
+    OpenOptions::new().write(true).open(&journal).unwrap().set_len(journal_len - 100).unwrap();
+    drop(fp);
+
+    let (volume, _) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert!(!fs::exists(&journal).unwrap());
+    assert_eq!(volume.get_actual_size(), 1);
+    assert!(volume.offsets.contains_key(&chunks[0].uid));
+
+    // Appended frames are replayed up to a torn one
+    write_journal(&journal, &records).unwrap();
+    append_journal(&journal, &[JournalRecord {
+        offset: 0,
+        data: metadata_before.clone(),
+    }]).unwrap();
+
+    let journal_len = fs::metadata(&journal).unwrap().len();
+    OpenOptions::new().write(true).open(&journal).unwrap().set_len(journal_len - 100).unwrap();
+
+    let (volume, _) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert!(!fs::exists(&journal).unwrap());
+    assert_eq!(volume.get_actual_size(), 2);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_failed_transaction() {
+    let vol_path = "./tmp/vol35033.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..2)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+    let invalid = Chunk {
+        uid: "not-a-uuid".to_string(),
+        data: vec![0u8; CHUNK_SIZE],
+        length: None,
+    };
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunk_v2(&fp, chunks[0].clone()).unwrap();
+    let data_head = volume.get_data_head();
+
+    // Nothing of a failed batch is left in memory either
+    let result = volume.add_chunks_v2(&fp, &vec![chunks[0].clone(), chunks[1].clone(), invalid]);
+    assert!(matches!(result, Err(XEngineError::InvalidUuid)));
+    assert_eq!(volume.get_actual_size(), 1);
+    assert_eq!(volume.get_data_head(), data_head);
+    assert!(!volume.offsets.contains_key(&chunks[1].uid));
+    assert_eq!(volume.chunks_meta[&chunks[0].uid].refcount, 1);
+    assert_eq!(volume.get_chunk_refcount(&fp, &chunks[0].uid).unwrap(), Some(1));
+
+    volume.add_chunk_v2(&fp, chunks[1].clone()).unwrap();
+
+    for chunk in chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_open_uncached() {
+    let vol_path = "./tmp/vol35015.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(64)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..64)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks[..48].to_vec()).unwrap();
+    drop(fp);
+
+    // Nothing but the superblock and the free extents is loaded
+    let (mut volume, fp) = Volume::open_existing_uncached(vol_path.to_string(), true).unwrap();
+    assert!(volume.offsets.is_empty());
+    assert_eq!(volume.get_actual_size(), 48);
+
+    for chunk in chunks[..48].iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+    assert!(volume.get_chunk_v2(&fp, chunks[48].uid.clone()).unwrap().is_none());
+
+    for chunk in chunks[..8].iter() {
+        volume.remove_chunk_v2(&fp, chunk.uid.clone()).unwrap();
+    }
+    volume.add_chunks_v2(&fp, &chunks[48..].to_vec()).unwrap();
+    assert!(volume.offsets.is_empty());
+    assert_eq!(volume.get_actual_size(), 56);
+
+    let live_chunks: HashSet<String> = chunks[8..].iter().map(|chunk| chunk.uid.clone()).collect();
+    let reclaimed = volume.compact(&fp, &live_chunks).unwrap();
+    assert_eq!(reclaimed, 0);
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    assert_eq!(volume.offsets.len(), 56);
+
+    for chunk in chunks[8..].iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_offset_map_tombstones() {
+    let vol_path = "./tmp/vol35040.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(8)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+    drop(volume);
+
+    let (mut volume, fp) = Volume::open_existing_uncached(vol_path.to_string(), true).unwrap();
+    let kept = Chunk {
+        uid: XFile::build_chunk_uid(Uuid::new_v4().to_string(), 0),
+        data: vec![0xAA; CHUNK_SIZE],
+        length: None,
+    };
+    volume.add_chunk_v2(&fp, kept.clone()).unwrap();
+
+    // Add and remove churn never leaves more than a quarter of the slots tombstoned
+    for round in 0..32 {
+        let file_uid = Uuid::new_v4().to_string();
+        let chunks: Vec<Chunk> = (0..4)
+            .map(|i| Chunk {
+                uid: XFile::build_chunk_uid(file_uid.clone(), i),
+                data: vec![round as u8; CHUNK_SIZE],
+                length: None,
+            })
+            .collect();
+
+        volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+        for chunk in chunks.iter() {
+            volume.remove_chunk_v2(&fp, chunk.uid.clone()).unwrap();
+            assert!(volume.map_offsets_tombstones * 4 <= volume.map_offsets_count);
+        }
+    }
+
+    let tombstones = volume.map_offsets_tombstones;
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing_uncached(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.map_offsets_tombstones, tombstones);
+    assert_eq!(volume.get_actual_size(), 1);
+    assert_eq!(volume.get_chunk_v2(&fp, kept.uid.clone()).unwrap().unwrap().data, kept.data);
+
+    // Rewriting the headers drops every tombstone
+    let mut fp = fp;
+    volume.write_headers(&mut fp).unwrap();
+    assert_eq!(volume.map_offsets_tombstones, 0);
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_compact_rehashes_after_purge() {
+    let vol_path = "./tmp/vol35042.rootfs";
+
+    // Purging most of a full volume passes the rehash threshold halfway through,
+    // the collisions moving entries depend on the random uids
+    for trial in 0..16 {
+        let mut volume = Volume::new();
+
+        volume
+            .set_path(vol_path.to_string())
+            .set_uid_from_device(DEVIDE_UID.into())
+            .set_max_size(32)
+            .build()
+            .unwrap();
+
+        fs::remove_file(vol_path).unwrap_or(());
+        volume.alloc_on_disk().unwrap();
+        drop(volume);
+
+        let cached = trial % 2 == 0;
+        let (mut volume, fp) = if cached {
+            Volume::open_existing(vol_path.to_string(), true).unwrap()
+        } else {
+            Volume::open_existing_uncached(vol_path.to_string(), true).unwrap()
+        };
+
+        let file_uid = Uuid::new_v4().to_string();
+        let chunks: Vec<Chunk> = (0..32)
+            .map(|i| Chunk {
+                uid: XFile::build_chunk_uid(file_uid.clone(), i),
+                data: vec![i as u8; CHUNK_SIZE],
+                length: None,
+            })
+            .collect();
+
+        volume.add_chunks_v2(&fp, &chunks).unwrap();
+
+        let live_chunks: HashSet<String> = chunks[..4].iter().map(|chunk| chunk.uid.clone()).collect();
+        volume.compact(&fp, &live_chunks).unwrap();
+        drop(fp);
+
+        let (volume, fp) = Volume::open_existing_uncached(vol_path.to_string(), false).unwrap();
+        assert_eq!(volume.get_actual_size(), 4);
+        let report = volume.scrub(&fp).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.ok.len(), 4);
+
+        for chunk in chunks.iter() {
+            let stored = volume.read_chunk(&fp, chunk.uid.clone()).unwrap();
+            assert_eq!(stored.map(|stored| stored.data), live_chunks.contains(&chunk.uid).then(|| chunk.data.clone()));
+        }
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn volume_test_mmap() {
+    let vol_path = "./tmp/vol35016.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..8)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &chunks[..2].to_vec()).unwrap();
+
+    let result = volume.get_chunk_mapped(&fp, &chunks[0].uid);
+    assert!(matches!(result, Err(XEngineError::VolumeNotMapped)));
+
+    volume.map(&fp).unwrap();
+
+    // Chunks written after the mapping are visible too
+    volume.add_chunks_v2(&fp, &chunks[2..4].to_vec()).unwrap();
+
+    for chunk in chunks[..4].iter() {
+        let stored = volume.get_chunk_mapped(&fp, &chunk.uid).unwrap().unwrap();
+        assert_eq!(stored, chunk.data.as_slice());
+    }
+    assert!(volume.get_chunk_mapped(&fp, &chunks[4].uid).unwrap().is_none());
+
+    // The whole grown file is mapped again
+    volume.resize(&fp, 8).unwrap();
+    assert!(volume.is_mapped());
+    volume.add_chunks_v2(&fp, &chunks[4..].to_vec()).unwrap();
+
+    for chunk in chunks.iter() {
+        let stored = volume.get_chunk_mapped(&fp, &chunk.uid).unwrap().unwrap();
+        assert_eq!(stored, chunk.data.as_slice());
+    }
+
+    // A clone could resize the file under the mapping, so it starts unmapped
+    let clone = volume.clone();
+    assert!(!clone.is_mapped());
+    assert!(matches!(clone.get_chunk_mapped(&fp, &chunks[0].uid), Err(XEngineError::VolumeNotMapped)));
+
+    volume.unmap();
+    assert!(!volume.is_mapped());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_chunk_size() {
+    let vol_path = "./tmp/vol35017.rootfs";
+    let chunk_size = 64 * 1024;
+
+    let assets_path = Path::new(ASSETS_FOLDER);
+    let file_path = assets_path.join("canterbury/alice29.txt");
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+
+    let file = XFile::new_with_chunk_size(user_uid, &file_path, "vfolder1".into(), chunk_size).unwrap();
+    assert_eq!(file.chunk_size(), chunk_size);
+    assert_eq!(file.chunks.len(), file.size.div_ceil(chunk_size));
+
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(file.chunks.len() as u64)
+        .set_chunk_size(chunk_size as u64)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &file.chunks).unwrap();
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.chunk_size, chunk_size as u64);
+    assert_eq!(volume.layout.data_start % volume.chunk_size, 0);
+    assert!(volume.layout.data_end - volume.layout.data_start >= volume.chunk_size * volume.max_size);
+
+    for chunk in file.chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // The chunk size is validated when the header is read, even with a valid checksum
+    let mut superblock = vec![0u8; 512];
+    fp.read_exact_at(&mut superblock, 0).unwrap();
+    superblock[112..120].copy_from_slice(&12345u64.to_le_bytes());
+    let checksum = compute_checksum(&superblock[..504]);
+    superblock[504..].copy_from_slice(&checksum.to_le_bytes());
+    fp.write_all_at(&superblock, 0).unwrap();
+    drop(fp);
+
+    let result = Volume::open_existing(vol_path.to_string(), false);
+    assert!(matches!(result, Err(XEngineError::InvalidChunkSize(12345))));
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_dedup_checksum_collision() {
+    let vol_path = "./tmp/vol35038.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    // Two different contents with the same CRC32C, found by a birthday search
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut seen = HashMap::new();
+
+    let (old_data, new_data) = loop {
+        let mut data = vec![0u8; 16];
+        rng.fill_bytes(&mut data);
+
+        match seen.insert(compute_checksum(&data), data.clone()) {
+            Some(other) if other != data => break (other, data),
+            _ => continue,
+        }
+    };
+
+    let uid = XFile::build_chunk_uid(Uuid::new_v4().to_string(), 0);
+    let fp = volume.open(true).unwrap();
+
+    volume.add_chunk_v2(&fp, Chunk { uid: uid.clone(), data: old_data, length: None }).unwrap();
+    volume.add_chunk_v2(&fp, Chunk { uid: uid.clone(), data: new_data.clone(), length: None }).unwrap();
+
+    // An indexed uid taking new data with a colliding checksum replaces the old data
+    assert_eq!(volume.get_chunk_refcount(&fp, &uid).unwrap(), Some(1));
+    assert_eq!(volume.get_chunk_v2(&fp, uid.clone()).unwrap().unwrap().data, new_data);
+
+    // The same data is still only referenced again
+    volume.add_chunk_v2(&fp, Chunk { uid: uid.clone(), data: new_data, length: None }).unwrap();
+    assert_eq!(volume.get_chunk_refcount(&fp, &uid).unwrap(), Some(2));
+    assert_eq!(volume.get_actual_size(), 1);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_oversized_chunk() {
+    let vol_path = "./tmp/vol35034.rootfs";
+
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let chunking = XFileChunking::ContentDefined {
+        min_size: 4096,
+        avg_size: 16384,
+        max_size: 65536,
+    };
+    let file = XFile::new_with_chunking(user_uid, &assets_file_path, "home".into(), chunking).unwrap();
+
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(file.chunks.len() as u64)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+
+    let oversized = Chunk {
+        uid: Uuid::new_v4().to_string(),
+        data: vec![1u8; CHUNK_SIZE + 1],
+        length: None,
+    };
+    let result = volume.add_chunk_v2(&fp, oversized);
+    assert!(matches!(result, Err(XEngineError::InvalidChunkSize(len)) if len == CHUNK_SIZE as u64 + 1));
+
+    // Content defined chunks bigger than the volume chunk size are refused as a whole
+    let result = volume.add_chunks_v2(&fp, &file.chunks);
+    assert!(matches!(result, Err(XEngineError::InvalidChunkSize(_))));
+    assert_eq!(volume.get_actual_size(), 0);
+    assert_eq!(volume.get_data_head(), volume.layout.data_start);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_dedup_refcount() {
+    let vol_path = "./tmp/vol35018.rootfs";
+
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let chunking = XFileChunking::Fixed { chunk_size: CHUNK_SIZE };
+
+    let file1 = XFile::new_with_addressing(user_uid, &assets_file_path, "home".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+    let file2 = XFile::new_with_addressing(user_uid, &assets_file_path, "backup".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+    let chunks_count = file1.chunks.len() as u64;
+
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(chunks_count)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &file1.chunks).unwrap();
+    let data_head = volume.get_data_head();
+
+    // The second file is stored by reference only, even on a full volume
+    volume.add_chunks_v2(&fp, &file2.chunks).unwrap();
+    assert_eq!(volume.get_actual_size(), chunks_count);
+    assert_eq!(volume.get_data_head(), data_head);
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+
+    for chunk_uid in file1.chunk_uids.iter() {
+        assert_eq!(volume.get_chunk_refcount(&fp, chunk_uid).unwrap(), Some(2));
+    }
+
+    volume.remove_file_chunks(&fp, &file1).unwrap();
+    assert_eq!(volume.get_actual_size(), chunks_count);
+    assert!(volume.free_extents.is_empty());
+
+    for chunk in file2.chunks.iter() {
+        assert_eq!(volume.get_chunk_refcount(&fp, &chunk.uid).unwrap(), Some(1));
+
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // The last reference frees the chunks
+    volume.remove_file_chunks(&fp, &file2).unwrap();
+    assert_eq!(volume.get_actual_size(), 0);
+    assert_eq!(volume.get_chunk_refcount(&fp, &file2.chunk_uids[0]).unwrap(), None);
+    assert!(volume.get_chunk_v2(&fp, file2.chunk_uids[0].clone()).unwrap().is_none());
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn volume_test_codec(codec: ChunkCodec, vol_path: &str) {
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let file = XFile::new(user_uid, &assets_file_path, "home".into()).unwrap();
+
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(file.chunks.len() as u64 + 1)
+        .set_codec(codec)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &file.chunks).unwrap();
+
+    // Text takes less than its raw size
+    let stored_len: u64 = volume.offsets.values().map(|offset| offset.len()).sum();
+    assert!(stored_len < file.size as u64, "{:?} stored {} of {} bytes", codec, stored_len, file.size);
+
+    // Random data does not compress and is stored raw
+    let mut noise = vec![0u8; CHUNK_SIZE];
+    StdRng::seed_from_u64(11).fill_bytes(&mut noise);
+    let noise = Chunk {
+        uid: Uuid::new_v4().to_string(),
+        data: noise,
+        length: None,
+    };
+    volume.add_chunk_v2(&fp, noise.clone()).unwrap();
+    assert_eq!(volume.offsets[&noise.uid].len(), CHUNK_SIZE as u64);
+    drop(fp);
+
+    // Chunks are decoded with the codec of their own entry
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    assert_eq!(volume.codec, ChunkCodec::None);
+
+    for chunk in file.chunks.iter().chain([&noise]) {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    drop(fp);
+
+    // The same chunks stored with another codec only gain a reference
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    let data_head = volume.get_data_head();
+    volume.add_chunks_v2(&fp, &file.chunks).unwrap();
+    assert_eq!(volume.get_data_head(), data_head);
+
+    for chunk in file.chunks.iter() {
+        assert_eq!(volume.get_chunk_refcount(&fp, &chunk.uid).unwrap(), Some(2));
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn volume_test_codec_zstd() {
+    volume_test_codec(ChunkCodec::Zstd, "./tmp/vol35019.rootfs");
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn volume_test_codec_lz4() {
+    volume_test_codec(ChunkCodec::Lz4, "./tmp/vol35020.rootfs");
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn volume_test_encryption() {
+    let vol_path = "./tmp/vol35021.rootfs";
+
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let file = XFile::new(user_uid, &assets_file_path, "home".into()).unwrap();
+    let chunks_count = file.chunks.len() as u64;
+
+    let key = ChunkKey::derive(&user_uid, b"correct horse battery staple");
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(chunks_count + 1)
+        .set_key(key.clone())
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &file.chunks).unwrap();
+
+    // Encryption is deterministic per chunk, so the same chunks are still deduplicated
+    volume.add_chunks_v2(&fp, &file.chunks).unwrap();
+    assert_eq!(volume.get_actual_size(), chunks_count);
+    assert_eq!(volume.get_chunk_refcount(&fp, &file.chunks[0].uid).unwrap(), Some(2));
+    drop(fp);
+
+    // No plaintext reaches the volume file
+    let raw = fs::read(vol_path).unwrap();
+    let plaintext = &file.chunks[0].data[..64];
+    assert!(!raw.windows(plaintext.len()).any(|window| window == plaintext));
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    let chunk_uid = file.chunks[0].uid.clone();
+
+    // Neither the plaintext length nor its checksum is left in the offset map
+    let meta = volume.chunks_meta[&chunk_uid];
+    assert_eq!((meta.raw_len, meta.raw_checksum), (0, 0));
+    let raw_checksum = compute_checksum(&file.chunks[0].data).to_le_bytes();
+    assert!(!raw.windows(raw_checksum.len()).any(|window| window == raw_checksum));
+
+    let result = volume.get_chunk_v2(&fp, chunk_uid.clone());
+    assert!(matches!(result, Err(XEngineError::EncryptionKeyRequired)));
+
+    // Scrub checks the stored bytes and needs no key
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    volume.set_key(ChunkKey::derive(&user_uid, b"wrong secret"));
+    let result = volume.get_chunk_v2(&fp, chunk_uid.clone());
+    assert!(matches!(result, Err(XEngineError::DecryptionFailed(uid)) if uid == chunk_uid));
+
+    // The key of another user does not open the chunks either
+    let other_uid = Uuid::new_v4();
+    volume.set_key(ChunkKey::derive(&other_uid, b"correct horse battery staple"));
+    assert!(volume.get_chunk_v2(&fp, chunk_uid.clone()).is_err());
+
+    volume.set_key(key);
+
+    for chunk in file.chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // A compressed chunk is decoded with the length sealed in its ciphertext
+    #[cfg(feature = "zstd")]
+    {
+        drop(fp);
+        let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+        volume.set_key(ChunkKey::derive(&user_uid, b"correct horse battery staple"));
+        volume.set_codec(ChunkCodec::Zstd);
+
+        let chunk = Chunk {
+            uid: Uuid::new_v4().to_string(),
+            data: vec![7u8; CHUNK_SIZE],
+            length: None,
+        };
+        volume.add_chunk_v2(&fp, chunk.clone()).unwrap();
+
+        let meta = volume.chunks_meta[&chunk.uid];
+        assert_eq!((meta.codec, meta.raw_len), (ChunkCodec::Zstd, 0));
+        assert_eq!(volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn volume_test_encrypted_content_addressing() {
+    let vol_path = "./tmp/vol35045.rootfs";
+    let passphrase = b"correct horse battery staple";
+
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let chunking = XFileChunking::Fixed { chunk_size: CHUNK_SIZE };
+
+    let mut file1 = XFile::new_with_addressing(user_uid, &assets_file_path, "home".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+    let mut file2 = XFile::new_with_addressing(user_uid, &assets_file_path, "backup".into(), chunking, XFileAddressing::ContentAddressed).unwrap();
+    let plain_uids = file1.chunk_uids.clone();
+    let chunks_count = file1.chunks.len() as u64;
+
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(chunks_count)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let fp = volume.open(true).unwrap();
+    volume.enable_encryption(&fp, passphrase).unwrap();
+
+    // A plain content hash would tell whoever reads the offset map which data the volume holds
+    let result = volume.add_chunks_v2(&fp, &file1.chunks);
+    assert!(matches!(result, Err(XEngineError::UnkeyedContentUid(_))));
+
+    volume.address_file(&mut file1).unwrap();
+    volume.address_file(&mut file2).unwrap();
+    assert_eq!(file1.chunk_uids, file2.chunk_uids);
+    assert!(file1.chunk_uids.iter().zip(plain_uids.iter()).all(|(keyed, plain)| keyed != plain));
+    assert!(!file1.verify_chunks());
+    assert!(volume.verify_file_chunks(&file1).unwrap());
+
+    // Files with the same content still share their chunks
+    volume.add_chunks_v2(&fp, &file1.chunks).unwrap();
+    volume.add_chunks_v2(&fp, &file2.chunks).unwrap();
+    assert_eq!(volume.get_actual_size(), chunks_count);
+    drop(fp);
+
+    let raw = fs::read(vol_path).unwrap();
+    for plain_uid in plain_uids.iter() {
+        let plain_uid = Uuid::parse_str(plain_uid).unwrap();
+        assert!(!raw.windows(16).any(|window| window == plain_uid.as_bytes()));
+    }
+
+    // The uids are derived from the data key, a locked volume cannot address a file
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert!(matches!(volume.address_file(&mut file1), Err(XEngineError::EncryptionKeyRequired)));
+
+    volume.unlock(passphrase).unwrap();
+    volume.address_file(&mut file1).unwrap();
+    assert_eq!(file1.chunk_uids, file2.chunk_uids);
+
+    for chunk in file1.chunks.iter() {
+        assert_eq!(volume.get_chunk_refcount(&fp, &chunk.uid).unwrap(), Some(2));
+
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn volume_test_key_management() {
+    let vol_path = "./tmp/vol35022.rootfs";
+
+    let assets_file_path = Path::new(ASSETS_FOLDER).join("canterbury/alice29.txt");
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let file = XFile::new(user_uid, &assets_file_path, "home".into()).unwrap();
+
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(file.chunks.len() as u64)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    // Chunks already stored would stay plaintext, so only an empty volume is encrypted
+    let fp = volume.open(true).unwrap();
+    volume.add_chunks_v2(&fp, &file.chunks[..2].to_vec()).unwrap();
+
+    assert!(matches!(volume.enable_encryption(&fp, b"passphrase 1"), Err(XEngineError::VolumeNotEmpty)));
+    assert!(!volume.is_encrypted());
+
+    for chunk in file.chunks[..2].iter() {
+        volume.remove_chunk_v2(&fp, chunk.uid.clone()).unwrap();
+    }
+    volume.compact(&fp, &HashSet::new()).unwrap();
+
+    volume.enable_encryption(&fp, b"passphrase 1").unwrap();
+    assert!(volume.is_encrypted());
+    assert!(matches!(volume.enable_encryption(&fp, b"passphrase 1"), Err(XEngineError::VolumeAlreadyEncrypted)));
+
+    volume.add_chunks_v2(&fp, &file.chunks).unwrap();
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert!(volume.is_encrypted());
+    assert!(matches!(volume.unlock(b"wrong passphrase"), Err(XEngineError::WrongKey)));
+    assert!(matches!(volume.get_chunk_v2(&fp, file.chunks[2].uid.clone()), Err(XEngineError::EncryptionKeyRequired)));
+
+    // Nothing is written until the volume is unlocked
+    let data_head = volume.get_data_head();
+    let chunk = Chunk {
+        uid: Uuid::new_v4().to_string(),
+        data: vec![7u8; CHUNK_SIZE],
+        length: None,
+    };
+    assert!(matches!(volume.add_chunk_v2(&fp, chunk.clone()), Err(XEngineError::EncryptionKeyRequired)));
+    assert!(matches!(volume.add_chunk_v2(&fp, file.chunks[0].clone()), Err(XEngineError::EncryptionKeyRequired)));
+    assert_eq!(volume.get_data_head(), data_head);
+    assert_eq!(volume.get_chunk_refcount(&fp, &file.chunks[0].uid).unwrap(), Some(1));
+
+    volume.unlock(b"passphrase 1").unwrap();
+
+    for chunk in file.chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // A new master key only rewraps the data key
+    let offsets = volume.offsets.clone();
+    volume.rotate_master_key(&fp, b"passphrase 1", b"passphrase 2").unwrap();
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert!(matches!(volume.unlock(b"passphrase 1"), Err(XEngineError::WrongKey)));
+    volume.unlock(b"passphrase 2").unwrap();
+
+    for chunk in file.chunks.iter() {
+        assert_eq!(volume.offsets[&chunk.uid].start, offsets[&chunk.uid].start);
+
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // A new data key re-encrypts every encrypted chunk in place
+    let mut old_stored = vec![0u8; offsets[&file.chunks[2].uid].len() as usize];
+    fp.read_exact_at(&mut old_stored, offsets[&file.chunks[2].uid].start).unwrap();
+
+    let reencrypted = volume.rotate_data_key(&fp, b"passphrase 2").unwrap();
+    assert_eq!(reencrypted, file.chunks.len() as u64);
+    assert_eq!(volume.rotate_data_key(&fp, b"passphrase 2").unwrap(), file.chunks.len() as u64);
+
+    for chunk in file.chunks.iter() {
+        assert_eq!(volume.offsets[&chunk.uid].start, offsets[&chunk.uid].start);
+    }
+    drop(fp);
+
+    let raw = fs::read(vol_path).unwrap();
+    assert!(!raw.windows(old_stored.len()).any(|window| window == old_stored.as_slice()));
+
+    let plaintext = &file.chunks[2].data[..64];
+    assert!(!raw.windows(plaintext.len()).any(|window| window == plaintext));
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    volume.unlock(b"passphrase 2").unwrap();
+
+    for chunk in file.chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+    drop(fp);
+
+    // Re-encrypted chunks are readable right away when the metadata sync is deferred
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    volume.unlock(b"passphrase 2").unwrap();
+    volume.set_durability(Durability::None);
+
+    volume.rotate_data_key(&fp, b"passphrase 2").unwrap();
+
+    for chunk in file.chunks.iter() {
+        let stored = volume.get_chunk_v2(&fp, chunk.uid.clone()).unwrap().unwrap();
+        assert_eq!(stored.data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_locking() {
+    let vol_path = "./tmp/vol35023.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(4)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    // A writer excludes both writers and readers
+    let writer = volume.open(true).unwrap();
+    assert!(matches!(volume.open(true), Err(XEngineError::VolumeLocked)));
+    assert!(matches!(volume.open(false), Err(XEngineError::VolumeLocked)));
+    assert!(matches!(Volume::open_existing(vol_path.to_string(), true), Err(XEngineError::VolumeLocked)));
+    drop(writer);
+
+    // Readers share the volume and exclude writers
+    let (_, reader1) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    let reader2 = volume.open(false).unwrap();
+    assert!(matches!(volume.open(true), Err(XEngineError::VolumeLocked)));
+    drop(reader1);
+    drop(reader2);
+
+    let writer = volume.open(true).unwrap();
+    drop(writer);
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_batched_io() {
+    let vol_path = "./tmp/vol35026.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(32)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    // Without the io-uring feature the engine falls back to positional I/O
+    assert_eq!(IoEngine::IoUring.is_available(), cfg!(all(feature = "io-uring", target_os = "linux")));
+    if !IoEngine::IoUring.is_available() {
+        assert_eq!(IoEngine::IoUring.active(), IoEngine::Sync);
+    } else if IoEngine::IoUring.active() != IoEngine::IoUring {
+        eprintln!("Skipping volume_test_batched_io: the kernel refused to set up an io_uring");
+        fs::remove_file(vol_path).unwrap_or(());
+        return;
+    }
+
+    // Uncached lookups go through the offset map, so the batch must see its own entries
+    let (mut volume, fp) = Volume::open_existing_uncached(vol_path.to_string(), true).unwrap();
+    volume.set_io_engine(IoEngine::IoUring);
+
+    let file_uid = Uuid::new_v4().to_string();
+    let mut chunks: Vec<Chunk> = (0..16)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+    chunks.push(chunks[0].clone());
+
+    volume.add_chunks_v2(&fp, &chunks).unwrap();
+    assert_eq!(volume.get_actual_size(), 16);
+    assert_eq!(volume.get_chunk_refcount(&fp, &chunks[0].uid).unwrap(), Some(2));
+
+    // A replaced chunk frees its old extent only once the batch data is written
+    let replaced = Chunk {
+        uid: chunks[1].uid.clone(),
+        data: vec![0xAB; CHUNK_SIZE],
+        length: None,
+    };
+    volume.add_chunks_v2(&fp, &vec![replaced.clone(), chunks[2].clone()]).unwrap();
+    assert_eq!(volume.get_actual_size(), 16);
+    assert_eq!(volume.free_extents.len(), 1);
+
+    let mut uids: Vec<String> = chunks[..16].iter().map(|chunk| chunk.uid.clone()).collect();
+    uids.push(Uuid::new_v4().to_string());
+
+    let stored = volume.read_chunks(&fp, &uids).unwrap();
+    assert!(stored[16].is_none());
+    assert_eq!(stored[1].as_ref().unwrap().data, replaced.data);
+
+    for (index, chunk) in chunks[..16].iter().enumerate().filter(|(index, _)| *index != 1) {
+        assert_eq!(stored[index].as_ref().unwrap().data, chunk.data, "Chunk {} data mismatch", chunk.uid);
+    }
+
+    // A batch larger than the free capacity fails instead of panicking halfway
+    let overflow: Vec<Chunk> = (16..33)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+    assert!(matches!(volume.add_chunks_v2(&fp, &overflow), Err(XEngineError::VolumeFull)));
+
+    drop(fp);
+
+    let (volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    assert_eq!(volume.get_actual_size(), 16);
+    assert_eq!(volume.read_chunk(&fp, replaced.uid.clone()).unwrap().unwrap().data, replaced.data);
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+#[test]
+fn volume_test_durability() {
+    let vol_path = "./tmp/vol35028.rootfs";
+    let mut volume = Volume::new();
+
+    volume
+        .set_path(vol_path.to_string())
+        .set_uid_from_device(DEVIDE_UID.into())
+        .set_max_size(16)
+        .build()
+        .unwrap();
+
+    fs::remove_file(vol_path).unwrap_or(());
+    volume.alloc_on_disk().unwrap();
+
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..12)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    let mut fp = volume.open(true).unwrap();
+
+    // Every chunk is synced before add_chunk_v2 returns by default
+    assert_eq!(volume.durability, Durability::PerChunk);
+    volume.add_chunk_v2(&fp, chunks[0].clone()).unwrap();
+    assert!(!volume.has_unsynced());
+
+    // Nothing reaches the volume metadata before the sync
+    let data_start = volume.layout.data_start as usize;
+    let metadata = fs::read(vol_path).unwrap()[..data_start].to_vec();
+
+    volume.set_durability(Durability::None);
+    volume.add_chunk_v2(&fp, chunks[1].clone()).unwrap();
+    volume.remove_chunk_v2(&fp, chunks[1].uid.clone()).unwrap();
+    assert!(volume.has_unsynced());
+    assert_eq!(fs::read(vol_path).unwrap()[..data_start], metadata[..]);
+    volume.flush(&fp).unwrap();
+    assert!(!volume.has_unsynced());
+
+    // Nothing would sync a group on time without a queue worker, the writes are refused
+    volume.set_durability(Durability::GroupCommit { interval_ms: 60_000, chunks: 4 });
+    let result = volume.add_chunks_v2(&fp, &chunks[2..5].to_vec());
+    assert!(matches!(result, Err(XEngineError::GroupCommitWithoutWorker)));
+    assert!(volume.read_chunk(&fp, chunks[2].uid.clone()).unwrap().is_none());
+
+    volume.set_durability(Durability::PerChunk);
+    volume.add_chunks_v2(&fp, &chunks[2..5].to_vec()).unwrap();
+    volume.add_chunk_v2(&fp, chunks[5].clone()).unwrap();
+    assert!(!volume.has_unsynced());
+
+    volume.set_durability(Durability::OnHeaderFlush);
+    volume.add_chunks_v2(&fp, &chunks[6..10].to_vec()).unwrap();
+    assert!(volume.has_unsynced());
+
+    // Chunks committed but not yet synced are scrubbed too
+    let report = volume.scrub(&fp).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.ok.len(), 9);
+    volume.write_headers(&mut fp).unwrap();
+    assert!(!volume.has_unsynced());
+
+    // A crashed process leaves the journal in the page cache, it is replayed on open
+    volume.set_durability(Durability::None);
+    volume.add_chunk_v2(&fp, chunks[10].clone()).unwrap();
+    drop(fp);
+
+    let (mut volume, fp) = Volume::open_existing(vol_path.to_string(), true).unwrap();
+    assert_eq!(volume.get_actual_size(), 10);
+
+    // A power loss also loses the unsynced journal, the volume is left as it was at the last sync
+    volume.set_durability(Durability::None);
+    volume.add_chunk_v2(&fp, chunks[11].clone()).unwrap();
+    drop(fp);
+    fs::remove_file(journal_path(vol_path)).unwrap();
+
+    let (volume, fp) = Volume::open_existing(vol_path.to_string(), false).unwrap();
+    assert_eq!(volume.get_actual_size(), 10);
+    assert!(volume.scrub(&fp).unwrap().is_clean());
+    assert!(volume.read_chunk(&fp, chunks[11].uid.clone()).unwrap().is_none());
+
+    for chunk in chunks[..11].iter().filter(|chunk| chunk.uid != chunks[1].uid) {
+        assert_eq!(volume.read_chunk(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+    }
+
+    fs::remove_file(vol_path).unwrap_or(());
+}
+
+// The extent freed by the removal is reused even before the next flush
+fn remove_and_add_when_full(volume: &mut Volume, fp: &File) {
+    let file_uid = Uuid::new_v4().to_string();
+    let chunks: Vec<Chunk> = (0..3)
+        .map(|i| Chunk {
+            uid: XFile::build_chunk_uid(file_uid.clone(), i),
+            data: vec![(i + 1) as u8; CHUNK_SIZE],
+            length: None,
+        })
+        .collect();
+
+    volume.add_chunks_v2(fp, &chunks[..2].to_vec()).unwrap();
+    volume.remove_chunk_v2(fp, chunks[0].uid.clone()).unwrap();
+    volume.add_chunk_v2(fp, chunks[2].clone()).unwrap();
+    volume.flush(fp).unwrap();
+
+    assert_eq!(volume.get_actual_size(), 2);
+    assert!(volume.read_chunk(fp, chunks[0].uid.clone()).unwrap().is_none());
+    for chunk in &chunks[1..] {
+        assert_eq!(volume.read_chunk(fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+    }
+    assert!(volume.scrub(fp).unwrap().is_clean());
+}
+
+#[test]
+fn volume_test_remove_and_add_when_full() {
+    let durabilities = [
+        Durability::PerChunk,
+        Durability::GroupCommit { interval_ms: 60_000, chunks: 4 },
+        Durability::OnHeaderFlush,
+        Durability::None,
+    ];
+
+    for (i, durability) in durabilities.into_iter().enumerate() {
+        let vol_path = format!("./tmp/vol35041_{i}.rootfs");
+        let mut volume = Volume::new();
+
+        volume
+            .set_path(vol_path.clone())
+            .set_uid_from_device(DEVIDE_UID.into())
+            .set_max_size(2)
+            .build()
+            .unwrap();
+
+        fs::remove_file(&vol_path).unwrap_or(());
+        volume.alloc_on_disk().unwrap();
+        volume.set_durability(durability);
+
+        let fp = volume.open(true).unwrap();
+
+        // Group commit needs a queue worker to sync its groups
+        if matches!(durability, Durability::GroupCommit { .. }) {
+            let queue = VolumeQueue::new(Arc::new(SharedVolume::new(volume, fp)));
+            remove_and_add_when_full(&mut queue.shared().write(), queue.shared().file());
+            queue.shutdown();
+        } else {
+            remove_and_add_when_full(&mut volume, &fp);
+        }
+
+        fs::remove_file(&vol_path).unwrap_or(());
+    }
+}
+
+#[test]
+fn volume_test_held_extents_after_restart() {
+    for (i, durability) in [Durability::OnHeaderFlush, Durability::None].into_iter().enumerate() {
+        let vol_path = format!("./tmp/vol35043_{i}.rootfs");
+        let mut volume = Volume::new();
+
+        volume
+            .set_path(vol_path.clone())
+            .set_uid_from_device(DEVIDE_UID.into())
+            .set_max_size(2)
+            .build()
+            .unwrap();
+
+        fs::remove_file(&vol_path).unwrap_or(());
+        volume.alloc_on_disk().unwrap();
+        volume.set_durability(durability);
+
+        let file_uid = Uuid::new_v4().to_string();
+        let chunks: Vec<Chunk> = (0..4)
+            .map(|i| Chunk {
+                uid: XFile::build_chunk_uid(file_uid.clone(), i),
+                data: vec![(i + 1) as u8; CHUNK_SIZE],
+                length: None,
+            })
+            .collect();
+
+        let fp = volume.open(true).unwrap();
+        volume.add_chunks_v2(&fp, &chunks[..2].to_vec()).unwrap();
+        volume.remove_chunk_v2(&fp, chunks[1].uid.clone()).unwrap();
+        assert!(volume.has_unsynced());
+        drop(fp);
+
+        // The extent of the removed chunk is free again once the journal is replayed
+        let (mut volume, fp) = Volume::open_existing(vol_path.clone(), true).unwrap();
+        assert_eq!(volume.get_actual_size(), 1);
+
+        volume.add_chunk_v2(&fp, chunks[2].clone()).unwrap();
+        assert!(matches!(volume.add_chunk_v2(&fp, chunks[3].clone()), Err(XEngineError::VolumeFull)));
+
+        for chunk in [&chunks[0], &chunks[2]] {
+            assert_eq!(volume.read_chunk(&fp, chunk.uid.clone()).unwrap().unwrap().data, chunk.data);
+        }
+        assert!(volume.scrub(&fp).unwrap().is_clean());
+
+        fs::remove_file(&vol_path).unwrap_or(());
+    }
+}
+
 fn volume_test_read_and_write_offsets(file_path: String, test_id: usize) {
     let assets_path = Path::new(ASSETS_FOLDER);
     let file_path = assets_path.join(file_path);
@@ -108,7 +2123,7 @@ fn volume_test_read_and_write_offsets(file_path: String, test_id: usize) {
         vol1.offsets.clear();
         vol1.chunks.clear();
 
-        vol1.read_headers(&mut fp, false).unwrap();
+        vol1.read_headers(&mut fp, true).unwrap();
 
         let new_chunks = vol1.offsets.clone();
 
@@ -18,10 +18,11 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 mod utils;
 
 use std::{
-    fs::{self}, path::Path
+    collections::HashSet, fs::{self}, io, path::Path
 };
 
-use xvault::engine::xfile::XFile;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use xvault::engine::xfile::{XFile, XFileChunking};
 use uuid::Uuid;
 
 
@@ -60,4 +61,70 @@ fn test_file(file_path: &str) {
     compare_files(&assets_file_path, &export_file_path);
 }
 
+fn shared_chunks(file1: &XFile, file2: &XFile) -> usize {
+    let data1: HashSet<&Vec<u8>> = file1.chunks.iter().map(|chunk| &chunk.data).collect();
+
+    return file2.chunks.iter().filter(|chunk| data1.contains(&chunk.data)).count();
+}
+
+#[test]
+fn xfile_test_content_defined_chunking() {
+    let file_path_1 = Path::new("./tmp/xfile_cdc_1.bin");
+    let file_path_2 = Path::new("./tmp/xfile_cdc_2.bin");
+    let export_file_path = Path::new(EXPORTS_FOLDER).join("xfile_cdc_1.bin");
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut data = vec![0u8; 1024 * 1024];
+    rng.fill_bytes(&mut data);
+
+    // Same data with one byte inserted near the start
+    let mut edited = data.clone();
+    edited.insert(100, 0xAA);
+
+    fs::create_dir_all("./tmp").unwrap();
+    fs::write(file_path_1, &data).unwrap();
+    fs::write(file_path_2, &edited).unwrap();
+
+    let user_uid = Uuid::parse_str(USER_UID).unwrap();
+    let chunking = XFileChunking::ContentDefined {
+        min_size: 4096,
+        avg_size: 16384,
+        max_size: 65536,
+    };
+
+    let file1 = XFile::new_with_chunking(user_uid, file_path_1, "/home".into(), chunking).unwrap();
+    let file2 = XFile::new_with_chunking(user_uid, file_path_2, "/home".into(), chunking).unwrap();
+
+    assert_eq!(file1.chunking, chunking);
+    assert_eq!(file1.chunk_size(), 65536);
+    assert!(file1.chunks.iter().all(|chunk| chunk.data.len() <= 65536 && chunk.length.is_none()));
+
+    // Only the chunks around the edit differ
+    let shared = shared_chunks(&file1, &file2);
+    assert!(shared + 2 >= file2.chunks.len(), "Only {} of {} chunks shared", shared, file2.chunks.len());
+
+    let fixed1 = XFile::new(user_uid, file_path_1, "/home".into()).unwrap();
+    let fixed2 = XFile::new(user_uid, file_path_2, "/home".into()).unwrap();
+    assert!(shared_chunks(&fixed1, &fixed2) <= 1);
+
+    // Invalid chunking parameters are rejected, not asserted
+    let invalid = XFileChunking::ContentDefined {
+        min_size: 65536,
+        avg_size: 16384,
+        max_size: 4096,
+    };
+    let result = XFile::new_with_chunking(user_uid, file_path_1, "/home".into(), invalid);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    let result = XFile::new_with_chunk_size(user_uid, file_path_1, "/home".into(), 1000);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    fs::remove_file(export_file_path.clone()).unwrap_or(());
+    file1.export_path(&export_file_path).unwrap();
+    compare_files(&file_path_1.to_path_buf(), &export_file_path);
+
+    fs::remove_file(file_path_1).unwrap_or(());
+    fs::remove_file(file_path_2).unwrap_or(());
+}
+
 include!(concat!(env!("OUT_DIR"), "/generated_xfile_tests.rs"));
\ No newline at end of file